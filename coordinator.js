@@ -7,15 +7,18 @@ const LINES_PER_WORKER = 5;
 const { PNG } = require("pngjs");
 const Bluebird = require("bluebird");
 
-async function renderSample(args, scene, current_top) {
+async function renderSample(args, scene, current_top, worker) {
   let image = scene.image;
   let body = {
     ...scene,
     image: {
       ...image,
       samples: SAMPLES_PER_WORKER,
+      // every worker takes different samples, otherwise they would all
+      // trace exactly the same paths
+      first_sample: worker * SAMPLES_PER_WORKER,
       slice: {
-        height: LINES_PER_WORKER,
+        height: Math.min(LINES_PER_WORKER, image.height - current_top),
         top: current_top
       }
     }
@@ -32,29 +35,90 @@ async function renderSample(args, scene, current_top) {
   }
 }
 
-async function renderLine(args, scene, current_top) {
+async function renderLine(args, scene, current_top, accumulated) {
   let promises = [];
   for (let i = 0; i < SAMPLES_PER_WORKER; ++i) {
-    promises.push(renderSample(args, scene, current_top));
+    promises.push(renderSample(args, scene, current_top, i));
   }
   let results = await Promise.all(promises);
 
-  let final_pixels = [];
+  // workers send back the weighted sum of their samples for each pixel,
+  // including a few rows either side of their slice that the filter
+  // spreads samples into, so add those up wherever they land and only
+  // tone map once everything is in
+  let width = scene.image.width;
+  for (let { film } of results) {
+    for (let i = 0; i < film.sums.length; ++i) {
+      let index = (film.top + Math.floor(i / width)) * width + (i % width);
+      for (let channel = 0; channel < 3; ++channel) {
+        accumulated.sums[index][channel] += film.sums[i][channel];
+      }
+      accumulated.weights[index] += film.weights[i];
+    }
+  }
+}
 
-  for (let i = 0; i < results[0].pixels.length; ++i) {
-    let pixel = [0, 0, 0, 255];
-    for (let result of results) {
-      pixel[0] += result.pixels[i][0];
-      pixel[1] += result.pixels[i][1];
-      pixel[2] += result.pixels[i][2];
+// These mirror libtrace's tonemap module, so the coordinator's images
+// match the ones the tracer writes
+function toneMap(toneMapping, radiance) {
+  let exposure = Math.pow(2, toneMapping.exposure || 0);
+  let color = radiance.map(channel => Math.max(0, channel * exposure));
+  switch ((toneMapping.operator || "Clamp").toLowerCase()) {
+    case "reinhard": {
+      let luminance =
+        0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2];
+      color = color.map(channel => channel / (1 + luminance));
+      break;
+    }
+    case "filmic": {
+      let hable = x =>
+        (x * (0.15 * x + 0.05) + 0.004) / (x * (0.15 * x + 0.5) + 0.06) -
+        0.02 / 0.3;
+      color = color.map(channel => hable(2 * channel) / hable(11.2));
+      break;
+    }
+    case "aces": {
+      color = color.map(channel => {
+        let x = channel * 0.6;
+        return (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+      });
+      break;
     }
-    pixel[0] /= SAMPLES_PER_WORKER;
-    pixel[1] /= SAMPLES_PER_WORKER;
-    pixel[2] /= SAMPLES_PER_WORKER;
-    final_pixels.push(pixel);
   }
-  return final_pixels;
+  return color.map(channel => Math.min(1, Math.max(0, channel)));
+}
+
+function linearToSrgb(value) {
+  return value <= 0.0031308
+    ? value * 12.92
+    : 1.055 * Math.pow(value, 1 / 2.4) - 0.055;
 }
+
+function ditherNoise(x, y, channel) {
+  let uniform = seed => {
+    let hash =
+      (Math.imul(x, 0x8da6b343) +
+        Math.imul(y, 0xd8163841) +
+        Math.imul(seed, 0xcb1ab31f)) >>>
+      0;
+    hash = (hash ^ (hash >>> 16)) >>> 0;
+    hash = Math.imul(hash, 0x7feb352d) >>> 0;
+    hash = (hash ^ (hash >>> 15)) >>> 0;
+    hash = Math.imul(hash, 0x846ca68b) >>> 0;
+    hash = (hash ^ (hash >>> 16)) >>> 0;
+    return hash / 4294967296;
+  };
+  return uniform(2 * channel) + uniform(2 * channel + 1) - 1;
+}
+
+function toRgb8(toneMapping, radiance, x, y) {
+  return toneMap(toneMapping, radiance).map((value, channel) => {
+    let noise = toneMapping.dither ? ditherNoise(x, y, channel) : 0;
+    let byte = linearToSrgb(value) * 255 + 0.5 + noise;
+    return Math.floor(Math.min(255, Math.max(0, byte)));
+  });
+}
+
 async function renderImage(args, scene) {
   let image = scene.image;
   let lineTops = [];
@@ -65,12 +129,16 @@ async function renderImage(args, scene) {
   ) {
     lineTops.push(current_top);
   }
-  let lineChunks = await Bluebird.map(
+  let numPixels = image.width * image.height;
+  let accumulated = {
+    sums: Array.from({ length: numPixels }, () => [0, 0, 0]),
+    weights: new Array(numPixels).fill(0)
+  };
+  await Bluebird.map(
     lineTops,
     async currentTop => {
-      let result = await renderLine(args, scene, currentTop);
+      await renderLine(args, scene, currentTop, accumulated);
       console.log("done with one segment ", currentTop);
-      return result;
     },
     {
       concurrency: 10
@@ -80,12 +148,22 @@ async function renderImage(args, scene) {
     width: image.width,
     height: image.height
   });
-  let i = 0;
-  for (let chunk of lineChunks) {
-    for (let pixel of chunk) {
-      for (let channel of pixel) {
-        png.data[i++] = channel;
-      }
+  let toneMapping = image.tone_mapping || {};
+  for (let i = 0; i < numPixels; ++i) {
+    // filters with negative lobes can leave almost no weight
+    let weight = accumulated.weights[i];
+    let radiance = accumulated.sums[i].map(channel =>
+      Math.abs(weight) < 1e-6 ? 0 : channel / weight
+    );
+    let pixel = toRgb8(
+      toneMapping,
+      radiance,
+      i % image.width,
+      Math.floor(i / image.width)
+    );
+    pixel.push(255);
+    for (let channel = 0; channel < 4; ++channel) {
+      png.data[i * 4 + channel] = pixel[channel];
     }
   }
 
@@ -0,0 +1,411 @@
+//! Renders a scene across a farm of workers. The image is split into
+//! tiles, and each tile into batches of samples, which are sent to the
+//! workers' `/render` endpoints. The films they send back are
+//! merged as floats before the image is written.
+
+use indicatif::{ProgressBar, ProgressStyle};
+use libtrace::exr::Precision;
+use libtrace::load::Files;
+use libtrace::output::{write_atomically, Format};
+use libtrace::scene::{Image, ImageSlice, Rendered, Scene};
+use libtrace::tiles::{Tile, Tiles};
+use libtrace::Film;
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+type JobError = Box<dyn Error + Send + Sync>;
+
+/// Some of the samples of a tile
+#[derive(Debug, Clone, PartialEq)]
+struct Job {
+    tile: Tile,
+    first_sample: u32,
+    samples: u32,
+    /// How many times this job has failed
+    failures: u32,
+}
+
+impl Job {
+    fn image(&self, image: &Image) -> Image {
+        Image {
+            slice: Some(ImageSlice {
+                left: self.tile.x,
+                top: self.tile.y,
+                width: Some(self.tile.width),
+                height: self.tile.height,
+            }),
+            first_sample: self.first_sample,
+            samples: self.samples,
+            total_samples: Some(image.total_samples()),
+            ..image.clone()
+        }
+    }
+
+    /// Merges what a worker sent back for the job into `film`, and
+    /// returns whatever's left of the job to do. Workers stop early if
+    /// they run out of time, so the rest of the samples go in another
+    /// job.
+    fn merge(mut self, rendered: &Rendered, film: &mut Film) -> Option<Job> {
+        film.merge(&rendered.film);
+        let taken = rendered.image.samples.min(self.samples);
+        if taken == self.samples {
+            return None;
+        }
+        self.first_sample += taken;
+        self.samples -= taken;
+        // A worker that can't take a single sample in time counts as a
+        // failure, so the job isn't retried forever
+        self.failures = if taken == 0 { self.failures + 1 } else { 0 };
+        Some(self)
+    }
+}
+
+/// Splits `image` into tiles of `tile_size` pixels, and each of those
+/// into batches of up to `samples_per_job` samples. Adaptive images
+/// stop sampling pixels once all of their samples so far say they've
+/// converged, so their tiles aren't split up, or a worker would only
+/// see some of them.
+fn jobs(image: &Image, tile_size: u32, samples_per_job: u32) -> Vec<Job> {
+    let tiles = Tiles {
+        size: tile_size,
+        order: image.tiles.order,
+    };
+    let samples_per_job = match image.adaptive {
+        Some(_) => image.samples,
+        None => samples_per_job,
+    };
+    let mut jobs = Vec::new();
+    for tile in tiles.split(image) {
+        for pass in image.passes(samples_per_job) {
+            jobs.push(Job {
+                tile,
+                first_sample: pass.first_sample,
+                samples: pass.samples,
+                failures: 0,
+            });
+        }
+    }
+    jobs
+}
+
+/// The body of a request to render `scene`. The workers load the scene
+/// from the files sent with it, so they don't need to see this
+/// machine's files.
+fn request(scene: &Scene) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mut request = serde_json::to_value(scene)?;
+    request["files"] = serde_json::to_value(Files::read(&scene.files)?)?;
+    Ok(request)
+}
+
+/// Sends jobs to the workers
+struct Client {
+    agent: ureq::Agent,
+    urls: Vec<String>,
+    /// The scene and the contents of its files, ready to have each
+    /// job's image swapped in
+    scene: serde_json::Value,
+    image: Image,
+}
+
+impl Client {
+    /// Renders `job`, on a different worker each time it's retried
+    fn render(&self, job: &Job, index: usize) -> Result<Rendered, JobError> {
+        let url = &self.urls[(index + job.failures as usize) % self.urls.len()];
+        let image = job.image(&self.image);
+        let mut body = self.scene.clone();
+        body["image"] = serde_json::to_value(&image)?;
+
+        let rendered: Rendered = match self.agent.post(url).send_json(body) {
+            Ok(response) => response.into_json()?,
+            Err(ureq::Error::Status(status, response)) => {
+                let message = response.into_string().unwrap_or_default();
+                return Err(format!("{} responded with {}: {}", url, status, message).into());
+            }
+            Err(err) => return Err(format!("couldn't reach {}: {}", url, err).into()),
+        };
+        let film = &rendered.film;
+        let expected = image.film();
+        if (film.left(), film.top(), film.width(), film.height())
+            != (
+                expected.left(),
+                expected.top(),
+                expected.width(),
+                expected.height(),
+            )
+        {
+            return Err(format!("{} sent back a film for the wrong pixels", url).into());
+        }
+        Ok(rendered)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = clap::App::new("Coordinator")
+        .version("0.1.0")
+        .about("Renders an image across a farm of workers")
+        .arg(
+            clap::Arg::with_name("output")
+                .short("o")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("image.png"),
+        )
+        .arg(
+            clap::Arg::with_name("input")
+                .short("i")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("scene.yml"),
+        )
+        .arg(
+            clap::Arg::with_name("url")
+                .long("url")
+                .value_name("URL")
+                .help("A worker's render endpoint. Give it more than once to share the work out.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("PIXELS")
+                .help("How many pixels wide and high the tile each job renders is")
+                .takes_value(true)
+                .default_value("64"),
+        )
+        .arg(
+            clap::Arg::with_name("samples")
+                .long("samples")
+                .value_name("SAMPLES")
+                .help("How many samples of each pixel each job takes")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            clap::Arg::with_name("concurrency")
+                .long("concurrency")
+                .value_name("JOBS")
+                .help("How many jobs can be rendering at once")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            clap::Arg::with_name("retries")
+                .long("retries")
+                .value_name("COUNT")
+                .help("How many times to retry a job that fails before giving up")
+                .takes_value(true)
+                .default_value("3"),
+        )
+        .arg(
+            clap::Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for a worker to respond")
+                .takes_value(true)
+                .default_value("90"),
+        )
+        .arg(
+            clap::Arg::with_name("precision")
+                .long("precision")
+                .value_name("PRECISION")
+                .help("How many bits to store each channel of an EXR image in")
+                .takes_value(true)
+                .possible_values(&["half", "float"])
+                .case_insensitive(true)
+                .default_value("half"),
+        )
+        .get_matches();
+
+    let scene = Scene::open(matches.value_of("input").unwrap())?;
+    let image = scene.image.clone();
+    let tile_size: u32 = matches.value_of("tile-size").unwrap().parse()?;
+    let samples_per_job: u32 = matches.value_of("samples").unwrap().parse()?;
+    let concurrency: usize = matches.value_of("concurrency").unwrap().parse()?;
+    let retries: u32 = matches.value_of("retries").unwrap().parse()?;
+    let timeout = Duration::from_secs(matches.value_of("timeout").unwrap().parse()?);
+    let precision: Precision = matches.value_of("precision").unwrap().parse()?;
+
+    let jobs = jobs(&image, tile_size, samples_per_job);
+    eprintln!("Jobs: {}", jobs.len());
+
+    let client = Client {
+        agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+        urls: matches
+            .values_of("url")
+            .unwrap()
+            .map(String::from)
+            .collect(),
+        scene: request(&scene)?,
+        image: image.clone(),
+    };
+    let progress_bar = ProgressBar::new(jobs.len() as u64);
+    progress_bar.set_style(ProgressStyle::default_bar().template(
+        "[{elapsed_precise} elapsed] {wide_bar:.green/white} {percent}% [{eta} remaining]",
+    ));
+
+    let mut film = image.visible_film();
+    let (job_sender, job_receiver) = mpsc::channel::<(usize, Job)>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+    let outstanding = jobs.len();
+    for job in jobs.into_iter().enumerate() {
+        job_sender.send(job)?;
+    }
+
+    thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+        for _ in 0..concurrency.max(1) {
+            let result_sender = result_sender.clone();
+            let (client, job_receiver) = (&client, &job_receiver);
+            scope.spawn(move || loop {
+                let received = job_receiver.lock().unwrap().recv();
+                let (index, job) = match received {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if job.failures > 0 {
+                    // Back off, in case the workers are overloaded
+                    thread::sleep(Duration::from_secs(1 << job.failures.min(5)));
+                }
+                let result = client.render(&job, index);
+                if result_sender.send((index, job, result)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        let mut outstanding = outstanding;
+        let result = (|| -> Result<(), Box<dyn Error>> {
+            while outstanding > 0 {
+                let (index, mut job, result) = result_receiver.recv()?;
+                match result {
+                    Ok(rendered) => match job.merge(&rendered, &mut film) {
+                        Some(rest) => job = rest,
+                        None => {
+                            outstanding -= 1;
+                            progress_bar.inc(1);
+                            continue;
+                        }
+                    },
+                    Err(err) => {
+                        progress_bar.println(format!("Job {} failed: {}", index, err));
+                        job.failures += 1;
+                    }
+                }
+                if job.failures > retries {
+                    return Err(format!("job {} failed {} times", index, job.failures).into());
+                }
+                job_sender.send((index, job))?;
+            }
+            Ok(())
+        })();
+        // Stops the threads once they've finished what they're doing
+        drop(job_sender);
+        result
+    })?;
+    progress_bar.finish();
+
+    let output_path = Path::new(matches.value_of("output").unwrap());
+    let format = Format::from_path(output_path, precision);
+    write_atomically(output_path, |output| {
+        format.write(output, &film, &image.tone_mapping)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtrace::Vec3;
+
+    fn image() -> Image {
+        serde_yaml::from_str("{width: 100, height: 70, samples: 40, slice: ~}").unwrap()
+    }
+
+    #[test]
+    fn test_jobs_cover_every_pixel_and_sample_once() {
+        let image = image();
+        let jobs = jobs(&image, 32, 16);
+        // 4 columns and 3 rows of tiles, each in batches of 16, 16 and 8
+        // samples
+        assert_eq!(jobs.len(), 4 * 3 * 3);
+        let mut samples = vec![0; 100 * 70];
+        for job in &jobs {
+            assert!(job.tile.width <= 32 && job.tile.height <= 32);
+            for (x, y) in job.tile.pixels() {
+                samples[(y * 100 + x) as usize] += job.samples;
+            }
+            // Each job's image is only its own tile and samples, out of
+            // all of the image's samples
+            let image = job.image(&image);
+            assert_eq!((image.left(), image.top()), (job.tile.x, job.tile.y));
+            assert_eq!(
+                (image.width(), image.height()),
+                (job.tile.width, job.tile.height)
+            );
+            assert_eq!(image.first_sample, job.first_sample);
+            assert_eq!(image.total_samples(), 40);
+        }
+        assert!(samples.iter().all(|&count| count == 40));
+        let last = jobs.last().unwrap();
+        assert_eq!(
+            last.tile,
+            Tile {
+                x: 96,
+                y: 64,
+                width: 4,
+                height: 6
+            }
+        );
+        assert_eq!((last.first_sample, last.samples), (32, 8));
+
+        let adaptive = Image {
+            adaptive: serde_yaml::from_str("{min_samples: 8}").unwrap(),
+            ..image
+        };
+        let adaptive_jobs = super::jobs(&adaptive, 32, 16);
+        assert_eq!(adaptive_jobs.len(), 4 * 3);
+        assert!(adaptive_jobs.iter().all(|job| job.samples == 40));
+    }
+
+    #[test]
+    fn test_partial_results_are_merged_and_the_rest_requeued() {
+        let image = image();
+        let job = jobs(&image, 32, 16).remove(0);
+        let rendered = |samples: u32| {
+            let image = Image {
+                samples,
+                ..job.image(&image)
+            };
+            let mut film = image.film();
+            film.add_sample((0, 0), Vec3::new(1., 1., 1.));
+            Rendered { image, film }
+        };
+        let mut film = image.visible_film();
+
+        // The worker ran out of time after 10 samples, so the other 6
+        // are sent again
+        let job = Job { failures: 2, ..job };
+        let rest = job.clone().merge(&rendered(10), &mut film).unwrap();
+        assert_eq!((rest.first_sample, rest.samples, rest.failures), (10, 6, 0));
+        assert_eq!(rest.tile, job.tile);
+        assert_eq!(film.samples((0, 0)), 1);
+
+        // Not taking any samples at all counts as failing
+        let retry = rest.clone().merge(&rendered(0), &mut film).unwrap();
+        assert_eq!(
+            (retry.first_sample, retry.samples, retry.failures),
+            (10, 6, 1)
+        );
+
+        // Finishing the rest leaves nothing to do
+        assert_eq!(retry.merge(&rendered(6), &mut film), None);
+        assert_eq!(film.samples((0, 0)), 3);
+        assert_eq!(film.pixel((0, 0)), Vec3::new(1., 1., 1.));
+    }
+}
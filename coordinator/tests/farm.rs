@@ -0,0 +1,163 @@
+//! Renders scenes with the coordinator, sharing them out to a worker
+//! server running in this process
+
+use libtrace::scene::Scene;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::Server;
+
+const SCENE: &str = "
+image:
+  width: 40
+  height: 30
+  samples: 12
+  slice: ~
+  filter: {type: Gaussian}
+sampler: Stratified
+camera: {look_from: [13, 2, 3], look_at: [0, 0, 0], aperture: 0.5, fov: 20}
+background: {type: Gradient, bottom: [1, 1, 1], top: [0.5, 0.7, 1]}
+objects:
+  type: StaticSphere
+  radius: 2
+  center: [0, 2, 0]
+  material: {type: Lambertian, albedo: {type: Noise, scale: 4}}
+";
+
+/// A quad textured by its MTL file, to be found relative to `MODEL_SCENE`
+const MODEL_SCENE: &str = "
+image: {width: 24, height: 16, samples: 4, slice: ~}
+camera: {look_from: [0.5, 0.5, 3], look_at: [0.5, 0.5, 0], aperture: 0, fov: 30}
+background: {type: Gradient, bottom: [1, 1, 1], top: [0.5, 0.7, 1]}
+objects: {type: Model, path: models/quad.obj}
+";
+const OBJ: &str = "mtllib quad.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+usemtl checks
+f 1/1 2/2 3/3 4/4
+";
+const MTL: &str = "newmtl checks
+Kd 1 1 1
+map_Kd checks.ppm
+";
+const PPM: &str = "P3 2 2 255
+255 0 0  0 255 0
+0 0 255  255 255 255
+";
+
+/// Starts a worker server, returning it along with its URL
+fn start_worker() -> (Arc<Server>, String) {
+    // Port 0 picks whichever one is free
+    let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+    let url = format!("http://{}/render", server.server_addr());
+    let serving = Arc::clone(&server);
+    thread::spawn(move || worker::server::serve(&serving, 2));
+    (server, url)
+}
+
+/// Checks that the PFM image at `output` is what rendering the scene at
+/// `scene_path` on one worker gives
+fn assert_same_as_one_worker(output: &Path, scene_path: &Path, size: (u32, u32)) {
+    let (width, height, pixels) = read_pfm(output);
+    assert_eq!((width, height), size);
+    let expected = worker::render(Scene::open(scene_path).unwrap()).film;
+    assert_eq!((expected.width(), expected.height()), size);
+    let expected = expected
+        .pixels()
+        .flat_map(|pixel| vec![pixel.x(), pixel.y(), pixel.z()]);
+    for (index, (pixel, expected)) in pixels.into_iter().zip(expected).enumerate() {
+        assert!(
+            (pixel - expected).abs() < 1e-5,
+            "channel {} is {}, not {}",
+            index,
+            pixel,
+            expected
+        );
+    }
+}
+
+/// The pixels of a PFM image, row by row from the top
+fn read_pfm(path: &Path) -> (u32, u32, Vec<f32>) {
+    let bytes = fs::read(path).unwrap();
+    let mut lines = bytes.splitn(4, |&byte| byte == b'\n');
+    assert_eq!(lines.next(), Some(&b"PF"[..]));
+    let size = String::from_utf8(lines.next().unwrap().to_vec()).unwrap();
+    let mut size = size.split(' ').map(|n| n.parse::<u32>().unwrap());
+    let (width, height) = (size.next().unwrap(), size.next().unwrap());
+    assert_eq!(lines.next(), Some(&b"-1.0"[..]));
+    let floats: Vec<f32> = lines
+        .next()
+        .unwrap()
+        .chunks(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    // Rows are stored from the bottom up
+    let rows = floats.chunks(width as usize * 3).rev().flatten().copied();
+    (width, height, rows.collect())
+}
+
+#[test]
+fn test_coordinator_renders_the_same_image_as_one_worker() {
+    let directory = std::env::temp_dir().join(format!("coordinator-farm-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let scene_path = directory.join("scene.yml");
+    fs::write(&scene_path, SCENE).unwrap();
+    let output = directory.join("image.pfm");
+
+    let (server, url) = start_worker();
+
+    // Tiles that don't divide the image, and batches that don't divide
+    // the samples, with the filter reaching across all of their edges
+    let status = Command::new(env!("CARGO_BIN_EXE_coordinator"))
+        .arg("-i")
+        .arg(&scene_path)
+        .arg("-o")
+        .arg(&output)
+        .args(["--url", &url, "--tile-size", "16", "--samples", "5"])
+        .status()
+        .unwrap();
+    server.unblock();
+    assert!(status.success());
+    assert!(!directory.join("image.pfm.partial").exists());
+
+    assert_same_as_one_worker(&output, &scene_path, (40, 30));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_workers_dont_need_the_scenes_files() {
+    let directory = std::env::temp_dir().join(format!("coordinator-files-{}", std::process::id()));
+    fs::create_dir_all(directory.join("models")).unwrap();
+    fs::write(directory.join("scene.yml"), MODEL_SCENE).unwrap();
+    fs::write(directory.join("models/quad.obj"), OBJ).unwrap();
+    fs::write(directory.join("models/quad.mtl"), MTL).unwrap();
+    fs::write(directory.join("models/checks.ppm"), PPM).unwrap();
+
+    // The coordinator finds the model relative to its own working
+    // directory, which isn't where the worker is looking
+    assert!(!Path::new("models").exists());
+    let (server, url) = start_worker();
+    let status = Command::new(env!("CARGO_BIN_EXE_coordinator"))
+        .current_dir(&directory)
+        .args(["-i", "scene.yml", "-o", "image.pfm", "--url", &url])
+        .status()
+        .unwrap();
+    server.unblock();
+    assert!(status.success());
+
+    assert_same_as_one_worker(
+        &directory.join("image.pfm"),
+        &directory.join("scene.yml"),
+        (24, 16),
+    );
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,271 @@
+//! Compares how quickly rays can be traced through `scenes/random.yml`
+//! with the different ways of organizing the scene, including the
+//! hierarchy of boxed nodes that the flattened one replaced.
+//!
+//! Run with `cargo bench -p libtrace --bench bvh`.
+
+use libtrace::sampler::Sampler;
+use libtrace::{
+    scene::Scene, Aabb, BvhOptions, Camera, HitRecord, Hitable, Ray, SplitMethod, Vec3,
+};
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 400;
+const HEIGHT: u32 = 200;
+const PASSES: u32 = 5;
+
+fn camera(scene: &Scene) -> Camera {
+    Camera::new(
+        scene.camera.look_from,
+        scene.camera.look_at,
+        Vec3::new(0., 1., 0.),
+        scene.camera.fov,
+        WIDTH as f32 / HEIGHT as f32,
+        0.,
+        1.,
+        0.,
+        1.,
+    )
+}
+
+fn rays(scene: &Scene) -> Vec<Ray> {
+    let camera = camera(scene);
+    let mut rays = Vec::with_capacity((WIDTH * HEIGHT) as usize);
+    for j in 0..HEIGHT {
+        for i in 0..WIDTH {
+            let u = (i as f32 + 0.5) / WIDTH as f32;
+            let v = (j as f32 + 0.5) / HEIGHT as f32;
+            let mut sampler = Sampler::new(scene.sampler, scene.seed, (i, j), 1);
+            rays.push(camera.get_ray(u, v, &mut sampler));
+        }
+    }
+    rays
+}
+
+/// How the hierarchy used to be laid out: every node boxes its two
+/// children, and a ray that hits a node's box visits both of them. It's
+/// built the same way as the flattened hierarchy, with binned SAH
+/// splits, so only the layout and traversal differ.
+enum BoxedBvh {
+    Leaf(Hitable),
+    Node {
+        bbox: Aabb,
+        left: Box<BoxedBvh>,
+        right: Box<BoxedBvh>,
+    },
+}
+
+const SAH_BINS: usize = 12;
+const TRAVERSAL_COST: f32 = 0.125;
+
+struct BuildItem {
+    hitable: Hitable,
+    bbox: Aabb,
+    centroid: Vec3,
+}
+
+fn bounds<'a>(boxes: impl Iterator<Item = &'a Aabb>) -> Option<Aabb> {
+    boxes.fold(None, |acc, bbox| match acc {
+        Some(acc) => Some(Aabb::surrounding_box(acc, *bbox)),
+        None => Some(*bbox),
+    })
+}
+
+fn axis_value(v: Vec3, axis: usize) -> f32 {
+    v.as_slice()[axis]
+}
+
+impl BoxedBvh {
+    fn new(objects: Hitable, options: &BvhOptions) -> BoxedBvh {
+        let items = match objects {
+            Hitable::List(list) => list.items().to_vec(),
+            other => vec![other],
+        };
+        let items = items
+            .into_iter()
+            .map(|hitable| {
+                let bbox = hitable.bounding_box((0., 1.));
+                let centroid = (bbox.min() + bbox.max()) * 0.5;
+                BuildItem {
+                    hitable,
+                    bbox,
+                    centroid,
+                }
+            })
+            .collect();
+        BoxedBvh::build(items, options)
+    }
+
+    fn leaf(mut items: Vec<BuildItem>) -> BoxedBvh {
+        if items.len() == 1 {
+            BoxedBvh::Leaf(items.pop().unwrap().hitable)
+        } else {
+            let items: Vec<Hitable> = items.into_iter().map(|item| item.hitable).collect();
+            BoxedBvh::Leaf(Hitable::List(items.into()))
+        }
+    }
+
+    fn build(mut items: Vec<BuildItem>, options: &BvhOptions) -> BoxedBvh {
+        if items.len() == 1 {
+            return BoxedBvh::leaf(items);
+        }
+        let bbox = bounds(items.iter().map(|item| &item.bbox)).unwrap();
+        let centroids = bounds(
+            items
+                .iter()
+                .map(|item| Aabb::new(item.centroid, item.centroid))
+                .collect::<Vec<_>>()
+                .iter(),
+        )
+        .unwrap();
+        let extent = centroids.max() - centroids.min();
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+            0
+        } else if extent.y() > extent.z() {
+            1
+        } else {
+            2
+        };
+        let can_be_leaf = items.len() <= options.max_leaf_size;
+        if axis_value(extent, axis) <= 0. && can_be_leaf {
+            return BoxedBvh::leaf(items);
+        }
+
+        let min = axis_value(centroids.min(), axis);
+        let bin_of = |item: &BuildItem| {
+            let offset = (axis_value(item.centroid, axis) - min) / axis_value(extent, axis);
+            ((offset * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+        };
+        let sah = match options.split {
+            SplitMethod::Sah if axis_value(extent, axis) > 0. => {
+                let mut counts = [0usize; SAH_BINS];
+                let mut boxes: [Option<Aabb>; SAH_BINS] = [None; SAH_BINS];
+                for item in &items {
+                    let bin = bin_of(item);
+                    counts[bin] += 1;
+                    boxes[bin] = bounds(boxes[bin].iter().chain(Some(&item.bbox)));
+                }
+                let mut best = (0, f32::INFINITY);
+                for split in 1..SAH_BINS {
+                    let (left, right) = boxes.split_at(split);
+                    let left_count: usize = counts[..split].iter().sum();
+                    let right_count: usize = counts[split..].iter().sum();
+                    if left_count == 0 || right_count == 0 {
+                        continue;
+                    }
+                    let left_area = bounds(left.iter().flatten()).unwrap().surface_area();
+                    let right_area = bounds(right.iter().flatten()).unwrap().surface_area();
+                    let cost = TRAVERSAL_COST
+                        + (left_area * left_count as f32 + right_area * right_count as f32)
+                            / bbox.surface_area();
+                    if cost < best.1 {
+                        best = (split, cost);
+                    }
+                }
+                Some(best)
+            }
+            _ => None,
+        };
+        let right = match sah {
+            Some((_, cost)) if can_be_leaf && cost >= items.len() as f32 => {
+                return BoxedBvh::leaf(items)
+            }
+            Some((split, _)) if split > 0 => {
+                let (left, right): (Vec<_>, Vec<_>) =
+                    items.into_iter().partition(|item| bin_of(item) < split);
+                items = left;
+                right
+            }
+            _ => {
+                items.sort_by(|a, b| {
+                    axis_value(a.centroid, axis).total_cmp(&axis_value(b.centroid, axis))
+                });
+                let mid = items.len() / 2;
+                items.split_off(mid)
+            }
+        };
+        BoxedBvh::Node {
+            bbox,
+            left: Box::new(BoxedBvh::build(items, options)),
+            right: Box::new(BoxedBvh::build(right, options)),
+        }
+    }
+
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        match self {
+            BoxedBvh::Leaf(hitable) => hitable.hit(ray, t_min, t_max),
+            BoxedBvh::Node { bbox, left, right } => {
+                if !bbox.hit(ray, t_min, t_max) {
+                    return None;
+                }
+                match (left.hit(ray, t_min, t_max), right.hit(ray, t_min, t_max)) {
+                    (Some(left), Some(right)) => Some(if left.t < right.t { left } else { right }),
+                    (left, right) => left.or(right),
+                }
+            }
+        }
+    }
+}
+
+fn trace(hits_anything: impl Fn(&Ray) -> bool, rays: &[Ray]) -> (Duration, usize) {
+    let start = Instant::now();
+    let mut hits = 0;
+    for _ in 0..PASSES {
+        for ray in rays {
+            if hits_anything(ray) {
+                hits += 1;
+            }
+        }
+    }
+    (start.elapsed(), hits)
+}
+
+fn main() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../scenes/random.yml");
+    let scene = Scene::open(path).unwrap();
+    let rays = rays(&scene);
+    let num_rays = rays.len() as f64 * PASSES as f64;
+
+    let median = BvhOptions {
+        split: SplitMethod::Median,
+        ..BvhOptions::default()
+    };
+    let sah = BvhOptions::default();
+    let list = scene.objects.clone();
+    let flat_median = scene.objects.clone().into_bvh((0., 1.), &median);
+    let flat_sah = scene.objects.clone().into_bvh((0., 1.), &sah);
+    let boxed_median = BoxedBvh::new(scene.objects.clone(), &median);
+    let boxed_sah = BoxedBvh::new(scene.objects.clone(), &sah);
+
+    let hit = |world: &Hitable, ray: &Ray| world.hit(ray, 0.001, f32::MAX).is_some();
+    let boxed_hit = |world: &BoxedBvh, ray: &Ray| world.hit(ray, 0.001, f32::MAX).is_some();
+    let results = [
+        ("list", trace(|ray| hit(&list, ray), &rays)),
+        (
+            "boxed (median)",
+            trace(|ray| boxed_hit(&boxed_median, ray), &rays),
+        ),
+        (
+            "boxed (sah)",
+            trace(|ray| boxed_hit(&boxed_sah, ray), &rays),
+        ),
+        ("bvh (median)", trace(|ray| hit(&flat_median, ray), &rays)),
+        ("bvh (sah)", trace(|ray| hit(&flat_sah, ray), &rays)),
+    ];
+
+    let seconds = |index: usize| (results[index].1).0.as_secs_f64();
+    for (index, (name, (_, hits))) in results.iter().enumerate() {
+        println!(
+            "{:>14}: {:>8.3} Mrays/s, {:>6.2}x the list ({} hits)",
+            name,
+            num_rays / seconds(index) / 1e6,
+            seconds(0) / seconds(index),
+            hits
+        );
+    }
+    println!(
+        "Flattening: {:.2}x with median splits, {:.2}x with SAH",
+        seconds(1) / seconds(3),
+        seconds(2) / seconds(4)
+    );
+}
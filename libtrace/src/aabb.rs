@@ -1,7 +1,7 @@
 use crate::{Ray, Vec3};
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct Aabb {
     min: Vec3,
     max: Vec3,
@@ -52,21 +52,37 @@ impl Aabb {
         self.max
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+    pub fn surface_area(&self) -> f32 {
+        let (x, y, z) = (self.max - self.min).to_tuple();
+        2. * (x * y + y * z + z * x)
+    }
+
+    /// The same as `hit`, but for when the inverse of the ray's
+    /// direction has already been computed, such as when testing the
+    /// same ray against many boxes.
+    #[inline]
+    pub fn hit_inverse(&self, origin: Vec3, inv_direction: Vec3, t_min: f32, t_max: f32) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
         for a in 0..3 {
-            let inv_d = 1.0 / ray.direction().as_slice()[a];
-            let mut t0 = (self.min.as_slice()[a] - ray.origin().as_slice()[a]) * inv_d;
-            let mut t1 = (self.max.as_slice()[a] - ray.origin().as_slice()[a]) * inv_d;
+            let inv_d = inv_direction.as_slice()[a];
+            let origin = origin.as_slice()[a];
+            let mut t0 = (self.min.as_slice()[a] - origin) * inv_d;
+            let mut t1 = (self.max.as_slice()[a] - origin) * inv_d;
             if inv_d < 0.0 {
                 std::mem::swap(&mut t0, &mut t1);
             }
-            let t_min = if t0 > t_min { t0 } else { t_min };
-            let t_max = if t1 <= t_max { t1 } else { t_max };
-
+            t_min = fmax(t0, t_min);
+            t_max = fmin(t1, t_max);
             if t_max <= t_min {
                 return false;
             }
         }
         true
     }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let inv_direction = ray.direction().apply(|v| 1. / v);
+        self.hit_inverse(ray.origin(), inv_direction, t_min, t_max)
+    }
 }
@@ -0,0 +1,435 @@
+//! What rays see when they escape the scene without hitting anything
+
+use crate::{lerp, load, Vec3};
+use image::GenericImageView;
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::f32::consts::PI;
+use std::fmt::{self, Debug};
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Background {
+    /// The same color in every direction
+    Constant {
+        color: Vec3,
+    },
+    /// Fades from `bottom`, looking straight down, to `top`, looking
+    /// straight up
+    Gradient {
+        bottom: Vec3,
+        top: Vec3,
+    },
+    Environment(Environment),
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Constant {
+            color: Vec3::default(),
+        }
+    }
+}
+
+impl Background {
+    /// The light arriving from `direction`, which doesn't need to be
+    /// normalized
+    pub fn value(&self, direction: Vec3) -> Vec3 {
+        match self {
+            Background::Constant { color } => *color,
+            Background::Gradient { bottom, top } => {
+                let t = 0.5 * (direction.into_normalized().y() + 1.);
+                lerp(*bottom, *top, t)
+            }
+            Background::Environment(environment) => environment.value(direction),
+        }
+    }
+}
+
+impl From<Environment> for Background {
+    fn from(environment: Environment) -> Background {
+        Background::Environment(environment)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct EnvironmentDescription {
+    path: String,
+    /// Scales the brightness of the whole image
+    #[serde(default = "EnvironmentDescription::default_intensity")]
+    intensity: f32,
+    /// Rotation around the y axis, in degrees
+    #[serde(default)]
+    rotation: f32,
+}
+
+impl EnvironmentDescription {
+    fn default_intensity() -> f32 {
+        1.
+    }
+}
+
+/// An equirectangular (latitude/longitude) image wrapped around the
+/// scene. The top of the image is straight up, and the middle of it is
+/// in the direction of -z before any rotation.
+///
+/// Radiance `.hdr` files are loaded as they are, and anything else is
+/// treated as an 8 bit sRGB image. Either way, bright parts of the
+/// image can be sampled directly to light the scene. In scene files the
+/// image's path is relative to the scene file.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "EnvironmentDescription", into = "EnvironmentDescription")]
+pub struct Environment {
+    description: EnvironmentDescription,
+    width: usize,
+    height: usize,
+    /// Linear colors, row by row from the top
+    pixels: Arc<Vec<Vec3>>,
+    /// Picks pixels in proportion to how much light they send towards
+    /// the scene
+    distribution: Arc<Distribution2D>,
+}
+
+impl Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Environment({}, {}x{})",
+            self.description.path, self.width, self.height
+        )
+    }
+}
+
+impl TryFrom<EnvironmentDescription> for Environment {
+    type Error = String;
+
+    fn try_from(mut description: EnvironmentDescription) -> Result<Environment, String> {
+        description.path = load::resolve(&description.path)
+            .to_string_lossy()
+            .into_owned();
+        let (width, height, pixels) = load_pixels(&description.path)
+            .map_err(|err| format!("couldn't load {}: {}", description.path, err))?;
+        if width == 0 || height == 0 {
+            return Err(format!("{} is empty", description.path));
+        }
+
+        // rows near the poles cover less of the sphere than the rows
+        // around the horizon, so they're less likely to be picked
+        let weights: Vec<Vec<f32>> = pixels
+            .chunks(width)
+            .enumerate()
+            .map(|(y, row)| {
+                let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+                row.iter()
+                    .map(|pixel| pixel.luminance().max(0.) * sin_theta)
+                    .collect()
+            })
+            .collect();
+
+        Ok(Environment {
+            description,
+            width,
+            height,
+            pixels: Arc::new(pixels),
+            distribution: Arc::new(Distribution2D::new(&weights)),
+        })
+    }
+}
+
+fn load_pixels(path: &str) -> image::ImageResult<(usize, usize, Vec<Vec3>)> {
+    let is_hdr = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+    if is_hdr {
+        let contents = load::read(Path::new(path))?;
+        let decoder = image::hdr::HDRDecoder::new(Cursor::new(contents))?;
+        let metadata = decoder.metadata();
+        let pixels = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .map(|pixel| {
+                let [r, g, b] = pixel.data;
+                Vec3::new(r, g, b)
+            })
+            .collect();
+        return Ok((metadata.width as usize, metadata.height as usize, pixels));
+    }
+
+    let image = load::image(Path::new(path))?;
+    let (width, height) = image.dimensions();
+    let pixels = image
+        .to_rgb()
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.data;
+            Vec3::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+        })
+        .collect();
+    Ok((width as usize, height as usize, pixels))
+}
+
+impl From<Environment> for EnvironmentDescription {
+    fn from(environment: Environment) -> EnvironmentDescription {
+        environment.description
+    }
+}
+
+/// Undoes the sRGB transfer function that 8 bit images are stored with
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = f32::from(value) / 255.;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl Environment {
+    /// Loads the image at `path`, with the given brightness and
+    /// rotation around the y axis in degrees
+    pub fn open<P: Into<String>>(
+        path: P,
+        intensity: f32,
+        rotation: f32,
+    ) -> Result<Environment, String> {
+        Environment::try_from(EnvironmentDescription {
+            path: path.into(),
+            intensity,
+            rotation,
+        })
+    }
+
+    pub fn value(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = self.direction_to_uv(direction);
+        self.lookup(u, v) * self.description.intensity
+    }
+
+    /// Picks a direction to look for light in, favouring the bright
+    /// parts of the image. `u` and `v` are uniformly distributed in
+    /// [0, 1). Returns the direction and the probability density of
+    /// picking it per unit solid angle.
+    pub fn sample(&self, (u, v): (f32, f32)) -> (Vec3, f32) {
+        let ((x, y), pdf) = self.distribution.sample((u, v));
+        let (u, v) = (x / self.width as f32, y / self.height as f32);
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        let phi = (u - 0.5) * 2. * PI + self.description.rotation.to_radians();
+        let direction = Vec3::new(sin_theta * phi.sin(), cos_theta, -sin_theta * phi.cos());
+        if sin_theta <= 0. {
+            return (direction, 0.);
+        }
+        (direction, self.solid_angle_pdf(pdf, sin_theta))
+    }
+
+    /// The probability density, per unit solid angle, of `sample`
+    /// picking `direction`
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0. {
+            return 0.;
+        }
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.solid_angle_pdf(self.distribution.pdf((x, y)), sin_theta)
+    }
+
+    /// Converts a density over the pixels of the image into a density
+    /// over directions
+    fn solid_angle_pdf(&self, pixel_pdf: f32, sin_theta: f32) -> f32 {
+        // each pixel covers 1 / (width * height) of the (u, v) square,
+        // which maps onto the sphere with a jacobian of 2 pi^2 sin(theta)
+        pixel_pdf * (self.width * self.height) as f32 / (2. * PI * PI * sin_theta)
+    }
+
+    /// Where `direction` lands on the image, with both coordinates in
+    /// [0, 1] and (0, 0) at the top left
+    fn direction_to_uv(&self, direction: Vec3) -> (f32, f32) {
+        let direction = direction.into_normalized();
+        let phi = direction.x().atan2(-direction.z()) - self.description.rotation.to_radians();
+        let u = (0.5 + phi / (2. * PI)).rem_euclid(1.);
+        let v = direction.y().clamp(-1., 1.).acos() / PI;
+        (u, v)
+    }
+
+    /// Bilinearly filters the image, wrapping around horizontally
+    fn lookup(&self, u: f32, v: f32) -> Vec3 {
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).max(0.);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+
+        let column = |x: f32| (x as isize).rem_euclid(self.width as isize) as usize;
+        let row = |y: f32| (y as usize).min(self.height - 1);
+        let pixel = |x: f32, y: f32| self.pixels[row(y) * self.width + column(x)];
+
+        let top = lerp(pixel(x0, y0), pixel(x0 + 1., y0), tx);
+        let bottom = lerp(pixel(x0, y0 + 1.), pixel(x0 + 1., y0 + 1.), tx);
+        lerp(top, bottom, ty)
+    }
+}
+
+/// A piecewise constant distribution over a list of values
+#[derive(Debug)]
+struct Distribution1D {
+    /// The running total of the values, normalized so the last is 1
+    cdf: Vec<f32>,
+    /// The chance of picking each value
+    probabilities: Vec<f32>,
+    total: f32,
+}
+
+impl Distribution1D {
+    fn new(values: &[f32]) -> Distribution1D {
+        let total: f32 = values.iter().sum();
+        let probabilities: Vec<f32> = if total > 0. {
+            values.iter().map(|value| value / total).collect()
+        } else {
+            // nothing stands out, so pick uniformly
+            vec![1. / values.len() as f32; values.len()]
+        };
+        let mut sum = 0.;
+        let mut cdf: Vec<f32> = probabilities
+            .iter()
+            .map(|probability| {
+                sum += probability;
+                sum
+            })
+            .collect();
+        if let Some(last) = cdf.last_mut() {
+            *last = 1.;
+        }
+        Distribution1D {
+            cdf,
+            probabilities,
+            total,
+        }
+    }
+
+    /// Picks a value, returning its index, how far through it `u` fell
+    /// and the chance of having picked it
+    fn sample(&self, u: f32) -> (usize, f32, f32) {
+        let index = self
+            .cdf
+            .iter()
+            .position(|&cdf| cdf > u)
+            .unwrap_or(self.cdf.len() - 1);
+        let start = if index == 0 { 0. } else { self.cdf[index - 1] };
+        let probability = self.probabilities[index];
+        let offset = if probability > 0. {
+            ((u - start) / probability).clamp(0., 0.9999)
+        } else {
+            0.5
+        };
+        (index, offset, probability)
+    }
+}
+
+/// Picks pixels from an image in proportion to their weights, by first
+/// picking a row and then a pixel within it
+#[derive(Debug)]
+struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    fn new(weights: &[Vec<f32>]) -> Distribution2D {
+        let rows: Vec<Distribution1D> =
+            weights.iter().map(|row| Distribution1D::new(row)).collect();
+        let totals: Vec<f32> = rows.iter().map(|row| row.total).collect();
+        Distribution2D {
+            marginal: Distribution1D::new(&totals),
+            rows,
+        }
+    }
+
+    /// Picks a point in pixel coordinates, returning it and the chance
+    /// of having picked the pixel it's in
+    fn sample(&self, (u, v): (f32, f32)) -> ((f32, f32), f32) {
+        let (y, y_offset, row_probability) = self.marginal.sample(v);
+        let (x, x_offset, probability) = self.rows[y].sample(u);
+        (
+            (x as f32 + x_offset, y as f32 + y_offset),
+            row_probability * probability,
+        )
+    }
+
+    fn pdf(&self, (x, y): (usize, usize)) -> f32 {
+        self.marginal.probabilities[y] * self.rows[y].probabilities[x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::fs::File;
+
+    #[test]
+    fn test_environment_directions() {
+        // red on the left half, blue on the right, and white along the
+        // bottom row
+        let mut image = image::RgbImage::new(4, 2);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            pixel.data = match (x, y) {
+                (_, 1) => [255, 255, 255],
+                (0, _) | (1, _) => [255, 0, 0],
+                _ => [0, 0, 255],
+            };
+        }
+        let path = std::env::temp_dir().join("libtrace-environment.png");
+        image.save(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let environment = Environment::open(path, 2., 0.).unwrap();
+        // halfway between -z and -x, and halfway up, is the middle of
+        // the red half
+        let left = environment.value(Vec3::new(-1., 2f32.sqrt(), -1.));
+        assert!((left - Vec3::new(2., 0., 0.)).length() < 1e-3, "{:?}", left);
+        assert!(
+            (environment.value(Vec3::new(0., -1., 0.)) - Vec3::new(2., 2., 2.)).length() < 1e-3
+        );
+
+        let rotated = Environment::open(path, 1., 180.).unwrap();
+        let left = rotated.value(Vec3::new(-1., 2f32.sqrt(), -1.));
+        assert!((left - Vec3::new(0., 0., 1.)).length() < 1e-3, "{:?}", left);
+    }
+
+    #[test]
+    fn test_hdr_importance_sampling() {
+        // a dim sky with one very bright pixel in it
+        let (width, height) = (16, 8);
+        let mut pixels = vec![
+            image::Rgb {
+                data: [0.1f32, 0.1, 0.1]
+            };
+            width * height
+        ];
+        pixels[2 * width + 5] = image::Rgb {
+            data: [1000., 1000., 1000.],
+        };
+        let path = std::env::temp_dir().join("libtrace-environment.hdr");
+        image::hdr::HDREncoder::new(File::create(&path).unwrap())
+            .encode(&pixels, width, height)
+            .unwrap();
+
+        let environment = Environment::open(path.to_str().unwrap(), 1., 30.).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut bright = 0;
+        for _ in 0..1000 {
+            let (direction, pdf) = environment.sample((rng.gen(), rng.gen()));
+            assert!((pdf - environment.pdf(direction)).abs() < 1e-3 * pdf);
+            if environment.value(direction).x() > 100. {
+                bright += 1;
+            }
+        }
+        assert!(
+            bright > 900,
+            "only {} samples found the bright pixel",
+            bright
+        );
+    }
+}
@@ -1,76 +1,525 @@
 use crate::aabb::Aabb;
-use crate::{HitRecord, Hitable, Ray};
+use crate::light::Shape;
+use crate::transform::Matrix4;
+use crate::{HitRecord, Hitable, Ray, Vec3};
 use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-use rand::Rng;
+/// How the items in a node get divided between its children
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SplitMethod {
+    /// Split the items in half along the longest axis
+    Median,
+    /// Split wherever the surface area heuristic says will be cheapest
+    /// to traverse
+    #[default]
+    Sah,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BvhNode {
-    left: Box<Hitable>,
-    right: Box<Hitable>,
+impl FromStr for SplitMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SplitMethod, String> {
+        match s.to_lowercase().as_str() {
+            "median" => Ok(SplitMethod::Median),
+            "sah" => Ok(SplitMethod::Sah),
+            _ => Err(format!("unknown BVH split method {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BvhOptions {
+    #[serde(default)]
+    pub split: SplitMethod,
+    /// The most items that will be grouped together in a leaf, rather
+    /// than being split up further
+    #[serde(default = "BvhOptions::default_max_leaf_size")]
+    pub max_leaf_size: usize,
+}
+
+impl Default for BvhOptions {
+    fn default() -> BvhOptions {
+        BvhOptions {
+            split: SplitMethod::default(),
+            max_leaf_size: BvhOptions::default_max_leaf_size(),
+        }
+    }
+}
+
+impl BvhOptions {
+    fn default_max_leaf_size() -> usize {
+        4
+    }
+}
+
+/// The number of buckets that items are sorted into when looking for
+/// the cheapest split
+const SAH_BINS: usize = 12;
+
+/// How expensive it is to traverse a node, relative to intersecting an
+/// item
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// The deepest that the SAH builder is allowed to go before falling
+/// back to splitting at the median. This keeps traversal from needing
+/// more than `MAX_STACK_SIZE` entries in its stack.
+const MAX_SAH_DEPTH: usize = 64;
+
+/// Median splits add at most one level per halving of the items
+const MAX_STACK_SIZE: usize = MAX_SAH_DEPTH + 64;
+
+/// An item being sorted into the hierarchy, along with its bounds
+struct BuildItem {
+    hitable: Hitable,
     bbox: Aabb,
+    centroid: Vec3,
+}
+
+fn bounds<'a>(items: impl Iterator<Item = &'a Aabb>) -> Option<Aabb> {
+    items.fold(None, |acc, bbox| match acc {
+        Some(acc) => Some(Aabb::surrounding_box(acc, *bbox)),
+        None => Some(*bbox),
+    })
+}
+
+fn centroid_bounds(items: &[BuildItem]) -> Aabb {
+    let first = items[0].centroid;
+    items[1..]
+        .iter()
+        .fold(Aabb::new(first, first), |acc, item| {
+            Aabb::surrounding_box(acc, Aabb::new(item.centroid, item.centroid))
+        })
+}
+
+fn sort_on_axis(items: &mut [BuildItem], axis: usize) {
+    items.sort_by(|a, b| {
+        let a = a.centroid.as_slice()[axis];
+        let b = b.centroid.as_slice()[axis];
+        a.total_cmp(&b)
+    });
+}
+
+fn bin_of(item: &BuildItem, axis: usize, min: f32, extent: f32) -> usize {
+    let offset = (item.centroid.as_slice()[axis] - min) / extent;
+    ((offset * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+}
+
+/// Finds the cheapest split along `axis` by binning the items'
+/// centroids. Returns the number of bins that go on the left side,
+/// along with the relative cost of splitting there.
+fn sah_split(items: &[BuildItem], bbox: &Aabb, centroids: &Aabb, axis: usize) -> (usize, f32) {
+    let min = centroids.min().as_slice()[axis];
+    let extent = centroids.max().as_slice()[axis] - min;
+
+    let mut counts = [0usize; SAH_BINS];
+    let mut boxes: [Option<Aabb>; SAH_BINS] = [None; SAH_BINS];
+    for item in items {
+        let bin = bin_of(item, axis, min, extent);
+        counts[bin] += 1;
+        boxes[bin] = bounds(boxes[bin].iter().chain(Some(&item.bbox)));
+    }
+
+    let parent_area = bbox.surface_area();
+    let mut best = (0, f32::INFINITY);
+    for split in 1..SAH_BINS {
+        let (left, right) = boxes.split_at(split);
+        let left_count: usize = counts[..split].iter().sum();
+        let right_count: usize = counts[split..].iter().sum();
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+        let left_area = bounds(left.iter().flatten()).unwrap().surface_area();
+        let right_area = bounds(right.iter().flatten()).unwrap().surface_area();
+        let cost = TRAVERSAL_COST
+            + (left_area * left_count as f32 + right_area * right_count as f32) / parent_area;
+        if cost < best.1 {
+            best = (split, cost);
+        }
+    }
+    best
+}
+
+#[derive(Debug, Clone)]
+enum NodeKind {
+    /// A range of `primitives`
+    Leaf { first: u32, count: u32 },
+    /// The first child always directly follows its parent, so only
+    /// the second child needs to be stored. `axis` is the axis the
+    /// children were split along.
+    Interior { second_child: u32, axis: u8 },
+}
+
+#[derive(Debug, Clone)]
+struct LinearNode {
+    bbox: Aabb,
+    kind: NodeKind,
+}
+
+/// The serialized form of a `BvhNode`. Only what the hierarchy was
+/// built from is stored, and it's rebuilt when it's deserialized, so a
+/// scene can't describe nodes that lead anywhere but its primitives.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct BvhDescription {
+    primitives: Vec<Hitable>,
+    #[serde(default = "BvhDescription::default_time")]
+    time: (f32, f32),
+    #[serde(default)]
+    options: BvhOptions,
+}
+
+impl BvhDescription {
+    fn default_time() -> (f32, f32) {
+        (0., 1.)
+    }
+}
+
+/// A bounding volume hierarchy, flattened into an array of nodes in
+/// depth first order so that traversal stays within a contiguous block
+/// of memory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "BvhDescription", into = "BvhDescription")]
+pub struct BvhNode {
+    nodes: Vec<LinearNode>,
+    primitives: Vec<Hitable>,
+    /// What the hierarchy was built with, for rebuilding it
+    time: (f32, f32),
+    options: BvhOptions,
+}
+
+impl From<BvhDescription> for BvhNode {
+    fn from(bvh: BvhDescription) -> BvhNode {
+        BvhNode::with_options(bvh.primitives, bvh.time, &bvh.options)
+    }
+}
+
+impl From<BvhNode> for BvhDescription {
+    fn from(bvh: BvhNode) -> BvhDescription {
+        BvhDescription {
+            primitives: bvh.primitives,
+            time: bvh.time,
+            options: bvh.options,
+        }
+    }
 }
 
 impl BvhNode {
-    pub fn new(mut hitables: Vec<Hitable>, time: (f32, f32)) -> BvhNode {
-        let axis = *rand::thread_rng().choose(&[0, 1, 2]).unwrap();
-
-        hitables.sort_by(|a, b| {
-            let a_bbox = a.bounding_box(time).min();
-            let b_bbox = b.bounding_box(time).min();
-            let a = a_bbox.as_slice()[axis];
-            let b = b_bbox.as_slice()[axis];
-            a.partial_cmp(&b).unwrap()
+    pub fn new(hitables: Vec<Hitable>, time: (f32, f32)) -> BvhNode {
+        BvhNode::with_options(hitables, time, &BvhOptions::default())
+    }
+
+    /// Builds a hierarchy over `hitables`. The same input always
+    /// produces the same hierarchy.
+    pub fn with_options(hitables: Vec<Hitable>, time: (f32, f32), options: &BvhOptions) -> BvhNode {
+        let items: Vec<BuildItem> = hitables
+            .into_iter()
+            .map(|hitable| {
+                let bbox = hitable.bounding_box(time);
+                let centroid = (bbox.min() + bbox.max()) * 0.5;
+                BuildItem {
+                    hitable,
+                    bbox,
+                    centroid,
+                }
+            })
+            .collect();
+
+        let mut bvh = BvhNode {
+            nodes: Vec::with_capacity(items.len() * 2),
+            primitives: Vec::with_capacity(items.len()),
+            time,
+            options: options.clone(),
+        };
+        if !items.is_empty() {
+            bvh.build(items, options, 0);
+        }
+        bvh
+    }
+
+    fn push_leaf(&mut self, items: Vec<BuildItem>, bbox: Aabb) {
+        self.nodes.push(LinearNode {
+            bbox,
+            kind: NodeKind::Leaf {
+                first: self.primitives.len() as u32,
+                count: items.len() as u32,
+            },
         });
+        self.primitives
+            .extend(items.into_iter().map(|item| item.hitable));
+    }
 
-        if hitables.len() == 1 {
-            let node = hitables[0].clone();
-            return BvhNode {
-                left: node.clone().into(),
-                right: node.clone().into(),
-                bbox: node.bounding_box(time),
-            };
-        } else if hitables.len() == 2 {
-            let left = hitables[0].clone();
-            let right = hitables[1].clone();
-            let bbox = Aabb::surrounding_box(left.bounding_box(time), right.bounding_box(time));
-
-            return BvhNode {
-                left: left.into(),
-                right: right.into(),
-                bbox,
-            };
+    fn build(&mut self, mut items: Vec<BuildItem>, options: &BvhOptions, depth: usize) {
+        let bbox = bounds(items.iter().map(|item| &item.bbox)).unwrap();
+        if items.len() == 1 {
+            return self.push_leaf(items, bbox);
         }
 
-        let last_half = hitables.split_off(hitables.len() / 2);
-        let first_half = hitables;
+        let centroids = centroid_bounds(&items);
+        let extent = centroids.max() - centroids.min();
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+            0
+        } else if extent.y() > extent.z() {
+            1
+        } else {
+            2
+        };
+
+        let can_be_leaf = items.len() <= options.max_leaf_size;
+        if extent.as_slice()[axis] <= 0. && can_be_leaf {
+            // everything is in the same place, so there's no good way to
+            // split these up
+            return self.push_leaf(items, bbox);
+        }
+
+        let sah = match options.split {
+            SplitMethod::Sah if extent.as_slice()[axis] > 0. && depth < MAX_SAH_DEPTH => {
+                Some(sah_split(&items, &bbox, &centroids, axis))
+            }
+            _ => None,
+        };
+        let right = match sah {
+            Some((_, cost)) if can_be_leaf && cost >= items.len() as f32 => {
+                return self.push_leaf(items, bbox);
+            }
+            Some((split, _)) if split > 0 => {
+                let min = centroids.min().as_slice()[axis];
+                let extent = extent.as_slice()[axis];
+                let (left, right): (Vec<_>, Vec<_>) = items
+                    .into_iter()
+                    .partition(|item| bin_of(item, axis, min, extent) < split);
+                items = left;
+                right
+            }
+            _ => {
+                sort_on_axis(&mut items, axis);
+                let mid = items.len() / 2;
+                items.split_off(mid)
+            }
+        };
+
+        let index = self.nodes.len();
+        self.nodes.push(LinearNode {
+            bbox,
+            kind: NodeKind::Interior {
+                second_child: 0,
+                axis: axis as u8,
+            },
+        });
+        self.build(items, options, depth + 1);
+        let second_child = self.nodes.len() as u32;
+        self.build(right, options, depth + 1);
+        self.nodes[index].kind = NodeKind::Interior {
+            second_child,
+            axis: axis as u8,
+        };
+    }
 
-        let left: Box<Hitable> = Hitable::from(BvhNode::new(first_half, time)).into();
-        let right: Box<Hitable> = Hitable::from(BvhNode::new(last_half, time)).into();
-        let bbox = Aabb::surrounding_box(left.bounding_box(time), right.bounding_box(time));
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats::default();
+        if !self.nodes.is_empty() {
+            self.record_stats(0, 0, &mut stats);
+        }
+        stats
+    }
 
-        BvhNode { left, right, bbox }
+    fn record_stats(&self, index: usize, depth: usize, stats: &mut BvhStats) {
+        stats.max_depth = stats.max_depth.max(depth);
+        match self.nodes[index].kind {
+            NodeKind::Leaf { count, .. } => {
+                let count = count as usize;
+                stats.leaves += 1;
+                stats.primitives += count;
+                stats.max_leaf_size = stats.max_leaf_size.max(count);
+            }
+            NodeKind::Interior { second_child, .. } => {
+                stats.nodes += 1;
+                self.record_stats(index + 1, depth + 1, stats);
+                self.record_stats(second_child as usize, depth + 1, stats);
+            }
+        }
     }
+
     pub fn bounding_box(&self) -> Aabb {
-        self.bbox.clone()
+        match self.nodes.first() {
+            Some(root) => root.bbox,
+            None => Aabb::new(Vec3::default(), Vec3::default()),
+        }
+    }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        for primitive in &self.primitives {
+            primitive.emitters(matrix, lights);
+        }
     }
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if !self.bbox.hit(ray, t_min, t_max) {
+
+    /// Walks the hierarchy front to back, visiting whichever child is
+    /// closer to the ray first so that hits found there can rule out
+    /// the other child entirely.
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
             return None;
         }
-        let hit_left = self.left.hit(ray, t_min, t_max);
-        let hit_right = self.right.hit(ray, t_min, t_max);
-        match (hit_left, hit_right) {
-            (Some(left), Some(right)) => {
-                if left.t < right.t {
-                    Some(left)
-                } else {
-                    Some(right)
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = direction.apply(|v| 1. / v);
+        let direction_is_negative = [direction.x() < 0., direction.y() < 0., direction.z() < 0.];
+
+        let mut closest_so_far = t_max;
+        let mut result = None;
+        let mut stack = [0u32; MAX_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node
+                .bbox
+                .hit_inverse(origin, inv_direction, t_min, closest_so_far)
+            {
+                match node.kind {
+                    NodeKind::Leaf { first, count } => {
+                        let first = first as usize;
+                        for primitive in &self.primitives[first..first + count as usize] {
+                            if let Some(hit_record) = primitive.hit(ray, t_min, closest_so_far) {
+                                closest_so_far = hit_record.t;
+                                result = Some(hit_record);
+                            }
+                        }
+                    }
+                    NodeKind::Interior { second_child, axis } => {
+                        let (near, far) = if direction_is_negative[axis as usize] {
+                            (second_child, current as u32 + 1)
+                        } else {
+                            (current as u32 + 1, second_child)
+                        };
+                        stack[stack_size] = far;
+                        stack_size += 1;
+                        current = near as usize;
+                        continue;
+                    }
                 }
             }
-            (Some(left), None) => Some(left),
-            (None, Some(right)) => Some(right),
-            (None, None) => None,
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = stack[stack_size] as usize;
+        }
+        result
+    }
+}
+
+/// Statistics about the shape of a hierarchy, to help see why a scene
+/// might be slow to render
+#[derive(Debug, Clone, Default)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub primitives: usize,
+    pub max_depth: usize,
+    pub max_leaf_size: usize,
+}
+
+impl BvhStats {
+    /// How many primitives there are in each leaf on average, or 0 if
+    /// the hierarchy is empty
+    pub fn mean_leaf_size(&self) -> f32 {
+        match self.leaves {
+            0 => 0.,
+            leaves => self.primitives as f32 / leaves as f32,
+        }
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes, {} leaves, {} primitives, depth {}, {:.2} primitives per leaf (max {})",
+            self.nodes,
+            self.leaves,
+            self.primitives,
+            self.max_depth,
+            self.mean_leaf_size(),
+            self.max_leaf_size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::Color;
+    use crate::StaticSphere;
+
+    fn spheres() -> Vec<Hitable> {
+        let mut spheres = Vec::new();
+        for i in 0..100 {
+            let x = (i % 10) as f32;
+            let z = (i / 10) as f32 * 3.;
+            let sphere = StaticSphere::new(0.4, (x, 0., z), Lambertian::new(Color::new(0.5)));
+            spheres.push(sphere.into());
+        }
+        spheres
+    }
+
+    #[test]
+    fn test_sah_is_deterministic() {
+        let options = BvhOptions::default();
+        let a = BvhNode::with_options(spheres(), (0., 1.), &options);
+        let b = BvhNode::with_options(spheres(), (0., 1.), &options);
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+
+        let stats = a.stats();
+        assert_eq!(stats.primitives, 100);
+        assert!(stats.max_leaf_size <= options.max_leaf_size);
+        let empty = BvhNode::with_options(Vec::new(), (0., 1.), &options).stats();
+        assert_eq!(empty.mean_leaf_size(), 0.);
+    }
+
+    #[test]
+    fn test_split_methods_agree() {
+        let ray = Ray::new(Vec3::new(3.2, 10., 6.1), Vec3::new(0., -1., 0.), 0.);
+        let mut hits = Vec::new();
+        for &split in &[SplitMethod::Median, SplitMethod::Sah] {
+            let options = BvhOptions {
+                split,
+                ..BvhOptions::default()
+            };
+            let bvh = BvhNode::with_options(spheres(), (0., 1.), &options);
+            hits.push(bvh.hit(&ray, 0.001, f32::MAX).unwrap().pointing_at);
         }
+        assert!((hits[0] - hits[1]).length() < 1e-5);
+    }
+
+    /// Only the primitives are sent along with a hierarchy, so there's
+    /// no way to describe one that traversal can't follow
+    #[test]
+    fn test_deserializing_rebuilds_the_hierarchy() {
+        let options = BvhOptions {
+            split: SplitMethod::Median,
+            max_leaf_size: 2,
+        };
+        let bvh = BvhNode::with_options(spheres(), (0., 1.), &options);
+        let yaml = serde_yaml::to_string(&bvh).unwrap();
+        assert!(!yaml.contains("nodes"));
+        let rebuilt: BvhNode = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            format!("{:?}", rebuilt.stats()),
+            format!("{:?}", bvh.stats())
+        );
+        let ray = Ray::new(Vec3::new(3.2, 10., 6.1), Vec3::new(0., -1., 0.), 0.);
+        assert_eq!(
+            rebuilt.hit(&ray, 0.001, f32::MAX).unwrap().pointing_at,
+            bvh.hit(&ray, 0.001, f32::MAX).unwrap().pointing_at
+        );
+
+        // Nodes that point past the end of the array are ignored
+        let empty: BvhNode = serde_yaml::from_str(
+            "{primitives: [], nodes: [{kind: {Interior: {second_child: 99, axis: 7}}}]}",
+        )
+        .unwrap();
+        assert!(empty.hit(&ray, 0.001, f32::MAX).is_none());
     }
 }
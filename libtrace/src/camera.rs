@@ -1,5 +1,5 @@
+use crate::sampler::Sampler;
 use crate::{Ray, Vec3};
-use rand::Rng;
 use std::f32;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -19,6 +19,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         lookfrom: Vec3,
         lookat: Vec3,
@@ -62,10 +63,11 @@ impl Camera {
     }
 
     #[inline]
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = Vec3::random_in_unit_circle() * self.lens_radius;
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut Sampler) -> Ray {
+        let rd = sample_disk(sampler.get_2d()) * self.lens_radius;
         let offset = self.u * rd.x() + self.v * rd.y();
-        let time = rand::thread_rng().gen_range(self.shutter_open_time, self.shutter_close_time);
+        let time = self.shutter_open_time
+            + sampler.get_1d() * (self.shutter_close_time - self.shutter_open_time);
 
         Ray::new(
             self.origin + offset,
@@ -74,3 +76,18 @@ impl Camera {
         )
     }
 }
+
+/// Maps a point in the unit square to the unit disk, keeping points
+/// that were spread evenly over the square spread evenly over the disk
+fn sample_disk((u, v): (f32, f32)) -> Vec3 {
+    let (x, y) = (2. * u - 1., 2. * v - 1.);
+    if x == 0. && y == 0. {
+        return Vec3::default();
+    }
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, f32::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (x / y))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.)
+}
@@ -0,0 +1,92 @@
+//! Saves how far a render has got, so it can carry on from there if
+//! it's stopped. Every sample's random numbers come from the scene's
+//! seed, the pixel and the sample's number, so the only random state to
+//! keep is how many samples have been taken.
+
+use crate::Film;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 8] = *b"TRACECKP";
+const VERSION: u32 = 2;
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// A fingerprint of the scene being rendered, to check that it
+    /// hasn't changed before resuming
+    pub scene_hash: u64,
+    /// How many samples of every pixel have been taken, counting from
+    /// the image's first sample
+    pub samples_taken: u32,
+    /// Everything that's been rendered so far
+    pub film: Film,
+}
+
+impl Checkpoint {
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.scene_hash.to_le_bytes())?;
+        writer.write_all(&self.samples_taken.to_le_bytes())?;
+        self.film.write_raw(writer)
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Checkpoint> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        if magic != MAGIC || u32::from_le_bytes(version) != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a checkpoint written by this version of the tracer",
+            ));
+        }
+        let mut scene_hash = [0; 8];
+        reader.read_exact(&mut scene_hash)?;
+        let mut samples_taken = [0; 4];
+        reader.read_exact(&mut samples_taken)?;
+        Ok(Checkpoint {
+            scene_hash: u64::from_le_bytes(scene_hash),
+            samples_taken: u32::from_le_bytes(samples_taken),
+            film: Film::read_raw(reader)?,
+        })
+    }
+}
+
+/// A 64 bit FNV-1a hash of `bytes`. Unlike the standard library's
+/// hasher, it's guaranteed to stay the same between builds, so it can
+/// be saved.
+pub fn fingerprint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vec3;
+
+    #[test]
+    fn test_checkpoints_round_trip() {
+        let mut film = Film::rows(2, 3, 2);
+        film.add_sample((1, 4), Vec3::new(0.5, 0.25, 2.));
+        film.add_sample((1, 4), Vec3::new(0.1, 0.2, 0.3));
+        let checkpoint = Checkpoint {
+            scene_hash: fingerprint(b"scene"),
+            samples_taken: 7,
+            film,
+        };
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes).unwrap();
+
+        let read = Checkpoint::read(&mut &bytes[..]).unwrap();
+        assert_eq!(read.scene_hash, checkpoint.scene_hash);
+        assert_eq!(read.samples_taken, 7);
+        assert_eq!((read.film.top(), read.film.height()), (3, 2));
+        assert_eq!(read.film.samples((1, 4)), 2);
+        assert_eq!(read.film.pixel((1, 4)), checkpoint.film.pixel((1, 4)));
+        assert_eq!(read.film.variance((1, 4)), checkpoint.film.variance((1, 4)));
+        assert!(Checkpoint::read(&mut &bytes[1..]).is_err());
+    }
+}
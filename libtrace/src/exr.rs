@@ -0,0 +1,256 @@
+//! Writes OpenEXR images. Only the subset we need is supported:
+//! uncompressed scanline images with any number of half or float
+//! channels.
+
+use crate::Film;
+use half::f16;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: u32 = 2;
+
+/// How many bits each channel value is stored in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Half,
+    Float,
+}
+
+impl Precision {
+    fn pixel_type(self) -> u32 {
+        match self {
+            Precision::Half => 1,
+            Precision::Float => 2,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Precision::Half => 2,
+            Precision::Float => 4,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Precision, String> {
+        match s.to_lowercase().as_str() {
+            "half" => Ok(Precision::Half),
+            "float" => Ok(Precision::Float),
+            _ => Err(format!("unknown EXR precision {:?}", s)),
+        }
+    }
+}
+
+/// One plane of the image, with a value for every pixel row by row from
+/// the top. Layers are written as channels that share a prefix, like
+/// `samples.Y`.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    pub fn new(name: impl Into<String>, values: Vec<f32>) -> Channel {
+        Channel {
+            name: name.into(),
+            values,
+        }
+    }
+}
+
+/// The channels to save for a film: the average color as `R`, `G` and
+/// `B`, how many samples went into each pixel in a `samples` layer, and
+/// the variance of each pixel's luminance in a `variance` layer
+pub fn film_channels(film: &Film) -> Vec<Channel> {
+    let pixels: Vec<_> = film.pixels().collect();
+    let mut samples = Vec::with_capacity(pixels.len());
+    let mut variance = Vec::with_capacity(pixels.len());
+    for y in film.top()..film.top() + film.height() {
+        for x in film.left()..film.left() + film.width() {
+            samples.push(film.samples((x, y)) as f32);
+            // Pixels with too few samples to tell are left at 0
+            variance.push(
+                Some(film.variance((x, y)))
+                    .filter(|v| v.is_finite())
+                    .unwrap_or(0.),
+            );
+        }
+    }
+    vec![
+        Channel::new("R", pixels.iter().map(|pixel| pixel.x()).collect()),
+        Channel::new("G", pixels.iter().map(|pixel| pixel.y()).collect()),
+        Channel::new("B", pixels.iter().map(|pixel| pixel.z()).collect()),
+        Channel::new("samples.Y", samples),
+        Channel::new("variance.Y", variance),
+    ]
+}
+
+pub fn write_film(writer: &mut impl Write, film: &Film, precision: Precision) -> io::Result<()> {
+    write(
+        writer,
+        (film.width(), film.height()),
+        film_channels(film),
+        precision,
+    )
+}
+
+/// Writes `channels`, which must each have `width * height` values
+pub fn write(
+    writer: &mut impl Write,
+    (width, height): (u32, u32),
+    mut channels: Vec<Channel>,
+    precision: Precision,
+) -> io::Result<()> {
+    let num_pixels = (width * height) as usize;
+    if width == 0 || height == 0 || channels.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't write an empty EXR image",
+        ));
+    }
+    if let Some(channel) = channels.iter().find(|c| c.values.len() != num_pixels) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "channel {:?} has {} values but the image has {} pixels",
+                channel.name,
+                channel.values.len(),
+                num_pixels
+            ),
+        ));
+    }
+    // Readers expect the channels in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut channel_list = Vec::new();
+    for channel in &channels {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&precision.pixel_type().to_le_bytes());
+        // pLinear and three reserved bytes
+        channel_list.extend_from_slice(&[0; 4]);
+        // x and y sampling
+        channel_list.extend_from_slice(&1u32.to_le_bytes());
+        channel_list.extend_from_slice(&1u32.to_le_bytes());
+    }
+    channel_list.push(0);
+    attribute(&mut header, "channels", "chlist", &channel_list);
+
+    attribute(&mut header, "compression", "compression", &[0]);
+    let mut window = Vec::new();
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // Increasing y, so the top row comes first
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // Every scanline is its own chunk, made of its y coordinate, the
+    // size of the data, then each channel's values for the row in turn
+    let line_size = width as usize * channels.len() * precision.size();
+    let chunk_size = 8 + line_size;
+    let first_chunk = header.len() + 8 * height as usize;
+    for y in 0..height as usize {
+        header.extend_from_slice(&((first_chunk + y * chunk_size) as u64).to_le_bytes());
+    }
+    writer.write_all(&header)?;
+
+    let mut line = Vec::with_capacity(chunk_size);
+    for y in 0..height as usize {
+        line.clear();
+        line.extend_from_slice(&(y as i32).to_le_bytes());
+        line.extend_from_slice(&(line_size as u32).to_le_bytes());
+        let row = y * width as usize..(y + 1) * width as usize;
+        for channel in &channels {
+            for &value in &channel.values[row.clone()] {
+                match precision {
+                    Precision::Half => line.extend_from_slice(&f16::from_f32(value).to_le_bytes()),
+                    Precision::Float => line.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+        }
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vec3;
+
+    #[test]
+    fn test_writes_scanlines_at_their_offsets() {
+        let mut film = Film::new(2, 3);
+        film.add_sample((1, 2), Vec3::new(3., 2., 1.));
+        film.add_sample((1, 2), Vec3::new(3., 2., 1.));
+
+        let mut exr = Vec::new();
+        write_film(&mut exr, &film, Precision::Float).unwrap();
+        assert_eq!(exr[..4], MAGIC);
+
+        // The offset table comes straight after the header, which ends
+        // with the last attribute's value and an empty name
+        let last_attribute = b"screenWindowWidth\0float\0";
+        let offsets_at = exr
+            .windows(last_attribute.len())
+            .position(|w| w == last_attribute)
+            .unwrap()
+            + last_attribute.len()
+            + 4
+            + 4
+            + 1;
+        let offset = |y: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&exr[offsets_at + 8 * y..offsets_at + 8 * (y + 1)]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        let last = offset(2);
+        assert_eq!(last, exr.len() - (8 + 2 * 5 * 4));
+        assert_eq!(exr[last..last + 4], 2i32.to_le_bytes());
+
+        // B, G, R, samples.Y then variance.Y, each for both pixels of
+        // the row
+        let value = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&exr[last + 8 + 4 * i..last + 12 + 4 * i]);
+            f32::from_le_bytes(bytes)
+        };
+        let row: Vec<_> = (0..10).map(value).collect();
+        assert_eq!(row, vec![0., 1., 0., 2., 0., 3., 0., 2., 0., 0.]);
+    }
+}
@@ -0,0 +1,498 @@
+//! Where rendered samples are collected
+
+use crate::filter::Filter;
+use crate::tonemap::ToneMapping;
+use crate::Vec3;
+use serde_derive::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The samples taken in a single pixel. Along with statistics about the
+/// samples themselves, they're spread over the pixels around it with a
+/// reconstruction filter, ready to be added to a film.
+#[derive(Debug, Clone)]
+pub struct PixelSamples {
+    /// The pixel the samples were taken in, counting from the top left
+    /// of the whole image
+    pub pixel: (u32, u32),
+    /// The unfiltered sum of the samples
+    pub sum: Vec3,
+    /// The sum of the square of each sample's luminance
+    pub squares: f32,
+    pub count: u32,
+    filter: Filter,
+    /// The weighted sum of the samples and the total weight for every
+    /// pixel the filter reaches, row by row from the top left
+    splats: Vec<(Vec3, f32)>,
+}
+
+impl PixelSamples {
+    pub fn new(pixel: (u32, u32), filter: Filter) -> PixelSamples {
+        let size = 2 * filter.pixel_radius() as usize + 1;
+        PixelSamples {
+            pixel,
+            sum: Vec3::default(),
+            squares: 0.,
+            count: 0,
+            filter,
+            splats: vec![(Vec3::default(), 0.); size * size],
+        }
+    }
+
+    /// Adds a sample taken `offset` into the pixel from its top left
+    /// corner
+    pub fn add(&mut self, (x, y): (f32, f32), radiance: Vec3) {
+        self.sum += radiance;
+        self.squares += radiance.luminance() * radiance.luminance();
+        self.count += 1;
+
+        let reach = self.filter.pixel_radius() as i32;
+        let size = 2 * reach + 1;
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let distance = (dx as f32 + 0.5 - x, dy as f32 + 0.5 - y);
+                let weight = self.filter.evaluate(distance);
+                if weight != 0. {
+                    let splat = &mut self.splats[((dy + reach) * size + dx + reach) as usize];
+                    splat.0 += radiance * weight;
+                    splat.1 += weight;
+                }
+            }
+        }
+    }
+
+    /// The unfiltered average of the samples, or black if there aren't
+    /// any
+    pub fn mean(&self) -> Vec3 {
+        match self.count {
+            0 => Vec3::default(),
+            count => self.sum / count as f32,
+        }
+    }
+
+    /// The estimated variance of the mean luminance, which shrinks as
+    /// more samples are taken
+    pub fn variance(&self) -> f32 {
+        variance_of_mean(self.sum.luminance(), self.squares, self.count)
+    }
+
+    /// The pixels the samples were spread over and what they add to
+    /// each
+    fn splats(&self) -> impl Iterator<Item = ((i64, i64), (Vec3, f32))> + '_ {
+        let reach = i64::from(self.filter.pixel_radius());
+        let size = 2 * reach + 1;
+        let (x, y) = (i64::from(self.pixel.0), i64::from(self.pixel.1));
+        self.splats.iter().enumerate().map(move |(index, &splat)| {
+            let index = index as i64;
+            ((x + index % size - reach, y + index / size - reach), splat)
+        })
+    }
+}
+
+fn variance_of_mean(luminance: f32, squares: f32, count: u32) -> f32 {
+    if count < 2 {
+        return f32::INFINITY;
+    }
+    let count = count as f32;
+    let mean = luminance / count;
+    let sample_variance = (squares - count * mean * mean).max(0.) / (count - 1.);
+    sample_variance / count
+}
+
+/// Accumulates the light arriving at each pixel of an image, or of a
+/// rectangle of it. Samples are kept as linear floating point sums
+/// along with their weights, so films rendered separately can be merged
+/// exactly, and nothing is rounded until the image is written out.
+///
+/// Pixels are always addressed by where they are in the whole image,
+/// counting from the top left.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Film {
+    /// The column of the whole image that the film starts at
+    #[serde(default)]
+    left: u32,
+    /// The row of the whole image that the film starts at
+    top: u32,
+    width: u32,
+    height: u32,
+    /// The weighted sum of the samples that reached each pixel, row by
+    /// row from the top
+    sums: Vec<Vec3>,
+    /// The total weight of the samples that reached each pixel
+    weights: Vec<f32>,
+    /// The sum of the luminance of every sample taken in each pixel,
+    /// and of their squares, for working out how noisy it is
+    luminance: Vec<f32>,
+    squares: Vec<f32>,
+    /// How many samples were taken in each pixel
+    samples: Vec<u32>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film::rows(width, 0, height)
+    }
+
+    /// A film that only covers `height` rows of an image, starting at
+    /// row `top`
+    pub fn rows(width: u32, top: u32, height: u32) -> Film {
+        Film::region((0, top), (width, height))
+    }
+
+    /// A film that only covers the `width` by `height` rectangle of an
+    /// image whose top left corner is at `(left, top)`
+    pub fn region((left, top): (u32, u32), (width, height): (u32, u32)) -> Film {
+        let num_pixels = (width * height) as usize;
+        Film {
+            left,
+            top,
+            width,
+            height,
+            sums: vec![Vec3::default(); num_pixels],
+            weights: vec![0.; num_pixels],
+            luminance: vec![0.; num_pixels],
+            squares: vec![0.; num_pixels],
+            samples: vec![0; num_pixels],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn top(&self) -> u32 {
+        self.top
+    }
+
+    pub fn left(&self) -> u32 {
+        self.left
+    }
+
+    #[inline]
+    fn index(&self, (x, y): (u32, u32)) -> usize {
+        debug_assert!(x >= self.left && x < self.left + self.width);
+        debug_assert!(y >= self.top && y < self.top + self.height);
+        ((y - self.top) * self.width + x - self.left) as usize
+    }
+
+    /// Where `(x, y)` is in the film's buffers, if it's on the film
+    fn checked_index(&self, (x, y): (i64, i64)) -> Option<usize> {
+        let (left, top) = (i64::from(self.left), i64::from(self.top));
+        if x < left || x >= left + i64::from(self.width) {
+            return None;
+        }
+        if y < top || y >= top + i64::from(self.height) {
+            return None;
+        }
+        Some(((y - top) * i64::from(self.width) + x - left) as usize)
+    }
+
+    /// Adds the samples taken in a pixel. Any that the filter spreads
+    /// off the edge of the film are left out, so a film that's missing
+    /// some of an image should include a margin that the filter
+    /// can reach into, for merging with the films next to it.
+    pub fn add_samples(&mut self, samples: &PixelSamples) {
+        let (x, y) = samples.pixel;
+        if let Some(index) = self.checked_index((i64::from(x), i64::from(y))) {
+            self.luminance[index] += samples.sum.luminance();
+            self.squares[index] += samples.squares;
+            self.samples[index] += samples.count;
+        }
+        for (location, (sum, weight)) in samples.splats() {
+            if let Some(index) = self.checked_index(location) {
+                self.sums[index] += sum;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// Adds a single sample from the very center of the pixel at
+    /// `(x, y)`
+    pub fn add_sample(&mut self, location: (u32, u32), radiance: Vec3) {
+        let mut samples = PixelSamples::new(location, Filter::default());
+        samples.add((0.5, 0.5), radiance);
+        self.add_samples(&samples);
+    }
+
+    /// The filtered color of the pixel at `(x, y)`, or black if no
+    /// samples reached it
+    pub fn pixel(&self, location: (u32, u32)) -> Vec3 {
+        let index = self.index(location);
+        pixel_value(self.sums[index], self.weights[index])
+    }
+
+    /// How many samples were taken in the pixel at `(x, y)`
+    pub fn samples(&self, location: (u32, u32)) -> u32 {
+        self.samples[self.index(location)]
+    }
+
+    /// The unfiltered average luminance of the samples taken in the
+    /// pixel at `(x, y)`
+    pub fn luminance(&self, location: (u32, u32)) -> f32 {
+        let index = self.index(location);
+        match self.samples[index] {
+            0 => 0.,
+            count => self.luminance[index] / count as f32,
+        }
+    }
+
+    /// The estimated variance of the mean luminance of the samples taken
+    /// in the pixel at `(x, y)`. It's infinite if there are too few to
+    /// tell.
+    pub fn variance(&self, location: (u32, u32)) -> f32 {
+        let index = self.index(location);
+        variance_of_mean(
+            self.luminance[index],
+            self.squares[index],
+            self.samples[index],
+        )
+    }
+
+    /// Every pixel's color, row by row from the top
+    pub fn pixels(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(&sum, &weight)| pixel_value(sum, weight))
+    }
+
+    /// Adds all of the samples in `other`, which must be for the same
+    /// image, to this film, wherever they overlap
+    pub fn merge(&mut self, other: &Film) {
+        let left = self.left.max(other.left);
+        let right = (self.left + self.width).min(other.left + other.width);
+        let top = self.top.max(other.top);
+        let bottom = (self.top + self.height).min(other.top + other.height);
+        for y in top..bottom.max(top) {
+            for x in left..right.max(left) {
+                let (to, from) = (self.index((x, y)), other.index((x, y)));
+                self.sums[to] += other.sums[from];
+                self.weights[to] += other.weights[from];
+                self.luminance[to] += other.luminance[from];
+                self.squares[to] += other.squares[from];
+                self.samples[to] += other.samples[from];
+            }
+        }
+    }
+
+    /// Writes everything the film holds, without losing any precision,
+    /// as little endian numbers
+    pub(crate) fn write_raw(&self, writer: &mut impl Write) -> io::Result<()> {
+        for value in &[self.left, self.top, self.width, self.height] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let mut bytes = Vec::with_capacity(self.samples.len() * 28);
+        for index in 0..self.samples.len() {
+            let sum = self.sums[index];
+            for value in &[
+                sum.x(),
+                sum.y(),
+                sum.z(),
+                self.weights[index],
+                self.luminance[index],
+                self.squares[index],
+            ] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&self.samples[index].to_le_bytes());
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Reads back a film written by `write_raw`
+    pub(crate) fn read_raw(reader: &mut impl Read) -> io::Result<Film> {
+        let mut read_u32 = || -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let (left, top) = (read_u32()?, read_u32()?);
+        let (width, height) = (read_u32()?, read_u32()?);
+        let mut film = Film::region((left, top), (width, height));
+        let mut bytes = vec![0; film.samples.len() * 28];
+        reader.read_exact(&mut bytes)?;
+        for (index, pixel) in bytes.chunks(28).enumerate() {
+            let value = |n: usize| {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&pixel[n * 4..n * 4 + 4]);
+                bytes
+            };
+            let float = |n: usize| f32::from_le_bytes(value(n));
+            film.sums[index] = Vec3::new(float(0), float(1), float(2));
+            film.weights[index] = float(3);
+            film.luminance[index] = float(4);
+            film.squares[index] = float(5);
+            film.samples[index] = u32::from_le_bytes(value(6));
+        }
+        Ok(film)
+    }
+
+    /// Tone maps the image and quantizes it to 8 bits per channel, row
+    /// by row from the top
+    pub fn to_rgb8(&self, tone_mapping: &ToneMapping) -> Vec<(u8, u8, u8)> {
+        let width = self.width;
+        self.pixels()
+            .enumerate()
+            .map(|(index, pixel)| {
+                let index = index as u32;
+                let location = (self.left + index % width, self.top + index / width);
+                tone_mapping.to_rgb8(pixel, location)
+            })
+            .collect()
+    }
+
+    /// Colors every pixel by how many samples it took, from dark blue
+    /// for the fewest through to yellow for the most
+    pub fn heatmap(&self) -> Vec<(u8, u8, u8)> {
+        const COLORS: [(f32, f32, f32); 5] = [
+            (0.05, 0.03, 0.53),
+            (0.42, 0.0, 0.66),
+            (0.8, 0.28, 0.47),
+            (0.99, 0.65, 0.21),
+            (0.94, 0.98, 0.13),
+        ];
+        let fewest = self.samples.iter().copied().min().unwrap_or(0);
+        let most = self.samples.iter().copied().max().unwrap_or(0);
+        self.samples
+            .iter()
+            .map(|&samples| {
+                let t = if most > fewest {
+                    (samples - fewest) as f32 / (most - fewest) as f32
+                } else {
+                    1.
+                };
+                let position = t * (COLORS.len() - 1) as f32;
+                let index = (position as usize).min(COLORS.len() - 2);
+                let (a, b) = (COLORS[index], COLORS[index + 1]);
+                let t = position - index as f32;
+                let channel = |a: f32, b: f32| ((a + (b - a) * t) * 255. + 0.5) as u8;
+                (channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+            })
+            .collect()
+    }
+}
+
+/// Filters with negative lobes can leave a pixel with almost no weight,
+/// so those are treated as empty rather than blowing up
+fn pixel_value(sum: Vec3, weight: f32) -> Vec3 {
+    if weight.abs() < 1e-6 {
+        Vec3::default()
+    } else {
+        sum / weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_averages_linear_samples() {
+        let mut first = Film::new(2, 1);
+        first.add_sample((0, 0), Vec3::new(1., 1., 1.));
+        for _ in 0..3 {
+            first.add_sample((1, 0), Vec3::new(1., 0., 0.));
+        }
+        let mut second = Film::new(2, 1);
+        for _ in 0..3 {
+            second.add_sample((0, 0), Vec3::new(0., 0., 0.));
+        }
+
+        first.merge(&second);
+        assert_eq!(first.samples((0, 0)), 4);
+        assert_eq!(first.pixel((0, 0)), Vec3::new(0.25, 0.25, 0.25));
+        assert_eq!(first.pixel((1, 0)), Vec3::new(1., 0., 0.));
+        // 0.25 is 137 in sRGB, where averaging the quantized samples
+        // would have given 63
+        assert_eq!(first.to_rgb8(&ToneMapping::default())[0], (137, 137, 137));
+        // One of four samples was 1 and the rest were 0, so the sample
+        // variance is 0.25, and a quarter of that for the mean
+        assert!((first.variance((0, 0)) - 0.0625).abs() < 1e-6);
+        assert!(first.variance((1, 0)) < 1e-6);
+    }
+
+    /// Rendering an image in two bands, each with a margin for the
+    /// filter to reach into, should come out the same as rendering it
+    /// all at once
+    #[test]
+    fn test_slices_stitch_together() {
+        let filter = Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        };
+        let samples = |y: u32| {
+            let mut samples = PixelSamples::new((1, y), filter);
+            samples.add((0.3, 0.8), Vec3::new(y as f32, 1., 0.));
+            samples.add((0.6, 0.1), Vec3::new(0., y as f32, 1.));
+            samples
+        };
+
+        let mut whole = Film::new(3, 4);
+        // Rows 0 and 1, and rows 2 and 3, each with a row of margin
+        let mut top = Film::rows(3, 0, 3);
+        let mut bottom = Film::rows(3, 1, 3);
+        for y in 0..4 {
+            whole.add_samples(&samples(y));
+            if y < 2 {
+                top.add_samples(&samples(y));
+            } else {
+                bottom.add_samples(&samples(y));
+            }
+        }
+        let mut stitched = Film::new(3, 4);
+        stitched.merge(&top);
+        stitched.merge(&bottom);
+        for y in 0..4 {
+            for x in 0..3 {
+                let difference = stitched.pixel((x, y)) - whole.pixel((x, y));
+                assert!(difference.length() < 1e-5, "{:?}", (x, y));
+            }
+            assert_eq!(stitched.samples((1, y)), 2);
+        }
+    }
+
+    /// The same goes for rectangles of the image, which the filter
+    /// spreads samples out of sideways as well
+    #[test]
+    fn test_regions_stitch_together() {
+        let filter = Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        };
+        let samples = |(x, y): (u32, u32)| {
+            let mut samples = PixelSamples::new((x, y), filter);
+            samples.add((0.3, 0.8), Vec3::new(x as f32, 1., y as f32));
+            samples
+        };
+
+        let mut whole = Film::new(4, 4);
+        let mut stitched = Film::new(4, 4);
+        for &(left, top) in &[(0, 0), (2, 0), (0, 2), (2, 2)] {
+            // Each 2x2 quarter, with a pixel of margin wherever it isn't
+            // at the edge of the image
+            let corner = (left.max(1) - 1, top.max(1) - 1);
+            let mut region = Film::region(corner, (3, 3));
+            for y in top..top + 2 {
+                for x in left..left + 2 {
+                    region.add_samples(&samples((x, y)));
+                }
+            }
+            stitched.merge(&region);
+        }
+        for y in 0..4 {
+            for x in 0..4 {
+                whole.add_samples(&samples((x, y)));
+            }
+        }
+        for y in 0..4 {
+            for x in 0..4 {
+                let difference = stitched.pixel((x, y)) - whole.pixel((x, y));
+                assert!(difference.length() < 1e-5, "{:?}", (x, y));
+                assert_eq!(stitched.samples((x, y)), 1);
+            }
+        }
+    }
+}
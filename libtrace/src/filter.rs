@@ -0,0 +1,173 @@
+//! Reconstruction filters, which decide how much each sample counts
+//! towards the pixels around it. Every filter is separable, so its
+//! weight is the product of its weights along x and y.
+
+use serde_derive::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Filter {
+    /// Every sample in the square counts the same. With the default
+    /// radius of half a pixel, that's a plain average of each pixel's
+    /// own samples.
+    Box {
+        #[serde(default = "Filter::half")]
+        radius: f32,
+    },
+    /// Weights fall off linearly to nothing at the radius
+    Tent {
+        #[serde(default = "Filter::one")]
+        radius: f32,
+    },
+    /// A Gaussian with standard deviation `sigma`, shifted down so it
+    /// reaches zero at the radius
+    Gaussian {
+        #[serde(default = "Filter::one_and_a_half")]
+        radius: f32,
+        #[serde(default = "Filter::half")]
+        sigma: f32,
+    },
+    /// The Mitchell-Netravali cubic, which sharpens slightly. `b` and
+    /// `c` default to a third each, as recommended by the paper.
+    Mitchell {
+        #[serde(default = "Filter::two")]
+        radius: f32,
+        #[serde(default = "Filter::third")]
+        b: f32,
+        #[serde(default = "Filter::third")]
+        c: f32,
+    },
+    /// A sinc windowed by a wider sinc, which keeps the most detail but
+    /// can ring around sharp edges
+    Lanczos {
+        #[serde(default = "Filter::three")]
+        radius: f32,
+    },
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    fn half() -> f32 {
+        0.5
+    }
+    fn one() -> f32 {
+        1.
+    }
+    fn one_and_a_half() -> f32 {
+        1.5
+    }
+    fn two() -> f32 {
+        2.
+    }
+    fn three() -> f32 {
+        3.
+    }
+    fn third() -> f32 {
+        1. / 3.
+    }
+
+    /// How far from the center, in pixels, the filter reaches
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    /// How many pixels either side of its own a sample can reach
+    pub fn pixel_radius(&self) -> u32 {
+        (self.radius() - 0.5).ceil().max(0.) as u32
+    }
+
+    /// The weight of a sample `(x, y)` pixels away from a pixel's
+    /// center
+    pub fn evaluate(&self, (x, y): (f32, f32)) -> f32 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.;
+        }
+        match *self {
+            Filter::Box { .. } => 1.,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f32| (-x * x / (2. * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.)
+            }
+            Filter::Mitchell { radius, b, c } => mitchell(2. * x / radius, b, c),
+            Filter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+/// The Mitchell-Netravali cubic, which is zero from 2 onwards
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    let x = x.abs();
+    let value = if x < 1. {
+        (12. - 9. * b - 6. * c) * x * x * x + (-18. + 12. * b + 6. * c) * x * x + (6. - 2. * b)
+    } else if x < 2. {
+        (-b - 6. * c) * x * x * x
+            + (6. * b + 30. * c) * x * x
+            + (-12. * b - 48. * c) * x
+            + (8. * b + 24. * c)
+    } else {
+        0.
+    };
+    value / 6.
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_peak_in_the_middle_and_vanish_past_the_radius() {
+        let filters: Vec<Filter> = serde_yaml::from_str(
+            "
+- {type: Box}
+- {type: Tent}
+- {type: Gaussian}
+- {type: Mitchell}
+- {type: Lanczos}
+",
+        )
+        .unwrap();
+        for filter in filters {
+            let center = filter.evaluate((0., 0.));
+            assert!(center > 0., "{:?}", filter);
+            assert!(filter.evaluate((0.4, 0.2)) <= center, "{:?}", filter);
+            let outside = filter.radius() + 0.01;
+            assert_eq!(filter.evaluate((outside, 0.)), 0., "{:?}", filter);
+        }
+        assert_eq!(Filter::default().pixel_radius(), 0);
+        assert_eq!(
+            Filter::Mitchell {
+                radius: 2.,
+                b: 0.,
+                c: 0.5
+            }
+            .pixel_radius(),
+            2
+        );
+    }
+}
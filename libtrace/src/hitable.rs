@@ -1,23 +1,38 @@
 use crate::aabb::Aabb;
+use crate::bvh::BvhOptions;
+use crate::light::{Sampling, Shape};
+use crate::obj::Model;
 use crate::rect::Rect;
 use crate::sphere::Sphere;
+use crate::transform::{Matrix4, Transform};
+use crate::triangle::{Triangle, TriangleMesh};
 use crate::{BvhNode, Material, MovingSphere, Ray, StaticSphere, Vec3};
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug)]
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     pub t: f32,
     pub pointing_at: Vec3,
+    /// The normal used for shading, which smooth meshes interpolate
+    /// between their vertices
     pub normal: Vec3,
+    /// The normal of the surface that was actually hit
+    pub geometric_normal: Vec3,
+    /// Whether light sampling could have picked the point that was hit
+    pub sampling: Sampling,
     pub uv: (f32, f32),
-    pub material: Material,
+    pub material: &'a Material,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NormalFlipper(pub Box<Hitable>);
+pub struct NormalFlipper {
+    pub inner: Box<Hitable>,
+}
 impl NormalFlipper {
     pub fn new<H: Into<Hitable>>(inner: H) -> NormalFlipper {
-        NormalFlipper(Box::new(inner.into()))
+        NormalFlipper {
+            inner: Box::new(inner.into()),
+        }
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +40,20 @@ pub struct HitableList {
     items: Vec<Hitable>,
 }
 
+impl HitableList {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn items(&self) -> &[Hitable] {
+        &self.items
+    }
+}
+
 impl From<Vec<Hitable>> for HitableList {
     fn from(items: Vec<Hitable>) -> HitableList {
         HitableList { items }
@@ -37,6 +66,10 @@ pub enum Hitable {
     StaticSphere(StaticSphere),
     MovingSphere(MovingSphere),
     Rect(Rect),
+    Triangle(Triangle),
+    TriangleMesh(TriangleMesh),
+    Model(Model),
+    Transform(Transform),
     BvhNode(BvhNode),
     List(HitableList),
     NormalFlipper(NormalFlipper),
@@ -55,6 +88,34 @@ impl From<MovingSphere> for Hitable {
     }
 }
 
+impl From<Triangle> for Hitable {
+    #[inline]
+    fn from(triangle: Triangle) -> Hitable {
+        Hitable::Triangle(triangle)
+    }
+}
+
+impl From<TriangleMesh> for Hitable {
+    #[inline]
+    fn from(mesh: TriangleMesh) -> Hitable {
+        Hitable::TriangleMesh(mesh)
+    }
+}
+
+impl From<Model> for Hitable {
+    #[inline]
+    fn from(model: Model) -> Hitable {
+        Hitable::Model(model)
+    }
+}
+
+impl From<Transform> for Hitable {
+    #[inline]
+    fn from(transform: Transform) -> Hitable {
+        Hitable::Transform(transform)
+    }
+}
+
 impl From<BvhNode> for Hitable {
     #[inline]
     fn from(node: BvhNode) -> Hitable {
@@ -76,16 +137,51 @@ impl<R: Into<Rect>> From<R> for Hitable {
 }
 
 impl Hitable {
+    /// Builds a bounding volume hierarchy over the contents of a list,
+    /// so that rays don't have to be tested against every item in it.
+    /// Nested lists are flattened into the hierarchy. Anything other
+    /// than a list is returned as is.
+    pub fn into_bvh(self, time: (f32, f32), options: &BvhOptions) -> Hitable {
+        fn flatten(hitable: Hitable, out: &mut Vec<Hitable>) {
+            match hitable {
+                Hitable::List(HitableList { items }) => {
+                    for item in items {
+                        flatten(item, out);
+                    }
+                }
+                other => out.push(other),
+            }
+        }
+
+        match self {
+            Hitable::List(list) => {
+                let mut items = Vec::with_capacity(list.items.len());
+                flatten(Hitable::List(list), &mut items);
+                match items.len() {
+                    0 => Hitable::List(items.into()),
+                    1 => items.pop().unwrap(),
+                    _ => BvhNode::with_options(items, time, options).into(),
+                }
+            }
+            other => other,
+        }
+    }
+
     #[inline]
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         match self {
             Hitable::StaticSphere(s) => s.hit(ray, t_min, t_max),
             Hitable::MovingSphere(s) => s.hit(ray, t_min, t_max),
             Hitable::Rect(rect) => rect.hit(ray, t_min, t_max),
+            Hitable::Triangle(triangle) => triangle.hit(ray, t_min, t_max),
+            Hitable::TriangleMesh(mesh) => mesh.hit(ray, t_min, t_max),
+            Hitable::Model(model) => model.hit(ray, t_min, t_max),
+            Hitable::Transform(transform) => transform.hit(ray, t_min, t_max),
             Hitable::BvhNode(node) => node.hit(ray, t_min, t_max),
-            Hitable::NormalFlipper(NormalFlipper(inner)) => {
+            Hitable::NormalFlipper(NormalFlipper { inner }) => {
                 let mut hit_record = inner.hit(ray, t_min, t_max)?;
                 hit_record.normal = -hit_record.normal;
+                hit_record.geometric_normal = -hit_record.geometric_normal;
                 Some(hit_record)
             }
             Hitable::List(HitableList { items: list }) => {
@@ -107,8 +203,12 @@ impl Hitable {
             Hitable::StaticSphere(s) => s.bounding_box(),
             Hitable::MovingSphere(s) => s.bounding_box(time),
             Hitable::Rect(rect) => rect.bounding_box(time),
+            Hitable::Triangle(triangle) => triangle.bounding_box(),
+            Hitable::TriangleMesh(mesh) => mesh.bounding_box(),
+            Hitable::Model(model) => model.bounding_box(time),
+            Hitable::Transform(transform) => transform.bounding_box(time),
             Hitable::BvhNode(node) => node.bounding_box(),
-            Hitable::NormalFlipper(NormalFlipper(inner)) => inner.bounding_box(time),
+            Hitable::NormalFlipper(NormalFlipper { inner }) => inner.bounding_box(time),
             Hitable::List(HitableList { items }) => {
                 let init = items[0].bounding_box(time);
                 items[1..].iter().fold(init, |prev, curr| {
@@ -117,4 +217,25 @@ impl Hitable {
             }
         }
     }
+
+    /// Adds the surface of everything that emits light to `lights`,
+    /// moved into world space by `matrix`
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        match self {
+            Hitable::StaticSphere(s) => s.emitters(matrix, lights),
+            Hitable::MovingSphere(s) => s.emitters(matrix, lights),
+            Hitable::Rect(rect) => rect.emitters(matrix, lights),
+            Hitable::Triangle(triangle) => triangle.emitters(matrix, lights),
+            Hitable::TriangleMesh(mesh) => mesh.emitters(matrix, lights),
+            Hitable::Model(model) => model.emitters(matrix, lights),
+            Hitable::Transform(transform) => transform.emitters(matrix, lights),
+            Hitable::BvhNode(node) => node.emitters(matrix, lights),
+            Hitable::NormalFlipper(NormalFlipper { inner }) => inner.emitters(matrix, lights),
+            Hitable::List(HitableList { items }) => {
+                for item in items {
+                    item.emitters(matrix, lights);
+                }
+            }
+        }
+    }
 }
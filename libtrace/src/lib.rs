@@ -1,27 +1,49 @@
 mod aabb;
+pub mod background;
 mod bvh;
 mod camera;
+pub mod checkpoint;
+pub mod exr;
+mod film;
+pub mod filter;
 mod hitable;
+pub mod light;
+pub mod load;
 pub mod material;
+pub mod obj;
+pub mod output;
 mod perlin;
+pub mod pfm;
 pub mod ppm;
+pub mod random;
 mod ray;
 pub mod rect;
 pub mod renderer;
+pub mod sampler;
 pub mod scene;
 mod sphere;
 pub mod texture;
+pub mod tiles;
+pub mod tonemap;
+pub mod transform;
+pub mod triangle;
 mod vec3;
 
-pub use bvh::BvhNode;
+pub use aabb::Aabb;
+pub use background::Background;
+pub use bvh::{BvhNode, BvhOptions, BvhStats, SplitMethod};
 pub use camera::Camera;
+pub use film::{Film, PixelSamples};
 pub use hitable::NormalFlipper;
 pub use hitable::{HitRecord, Hitable};
+pub use light::Lights;
 pub use material::{Material, Scatter};
 pub use ray::Ray;
 pub use sphere::{MovingSphere, StaticSphere};
 pub use vec3::Vec3;
 
+use sampler::Sampler;
+
 /// interpolate between two vectors. t is an indicator
 /// of how "far along" the interpolation should be. It should
 /// be a floating point number in [0, 1]
@@ -30,19 +52,116 @@ pub fn lerp(start_value: Vec3, end_value: Vec3, t: f32) -> Vec3 {
     start_value * (1.0 - t) + end_value * t
 }
 
-pub fn color(ray: &Ray, world: &Hitable, depth: i32) -> Vec3 {
-    match world.hit(ray, 0.001, std::f32::MAX) {
-        Some(hit_record) => {
-            let emitted = hit_record
-                .material
-                .emitted(hit_record.uv, hit_record.pointing_at);
-            if depth < 50 {
-                if let Some(scatter) = hit_record.material.scatter(ray, &hit_record) {
-                    return scatter.attenuation * color(&scatter.scatter, world, depth + 1);
+/// How many times a path can bounce before it's cut off
+const MAX_DEPTH: usize = 50;
+
+/// Trace a ray through the world. Rays that escape the scene take on
+/// the color of the background.
+///
+/// Wherever the path bounces off a diffuse surface, a shadow ray is
+/// also sent towards a random point on one of the `lights`. Light that
+/// could have been found either way is weighted between the two with
+/// the power heuristic, so small bright lights don't make the image
+/// noisy.
+pub fn color(
+    ray: &Ray,
+    world: &Hitable,
+    lights: &Lights,
+    background: &Background,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let mut ray = ray.clone();
+    let mut radiance = Vec3::default();
+    let mut throughput = Vec3::new(1., 1., 1.);
+    // how likely the last bounce was to pick the direction of `ray`,
+    // or `None` for camera rays and mirror-like bounces, which can
+    // only find lights by hitting them
+    let mut scattering_pdf: Option<f32> = None;
+
+    for depth in 0..=MAX_DEPTH {
+        sampler.start_bounce(depth);
+        let hit_record = match world.hit(&ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => {
+                let weight = match scattering_pdf {
+                    Some(pdf) => power_heuristic(pdf, lights.environment_pdf(ray.direction())),
+                    None => 1.,
+                };
+                radiance += throughput * background.value(ray.direction()) * weight;
+                break;
+            }
+        };
+        let material = hit_record.material;
+        if material.is_emissive() {
+            let weight = match scattering_pdf {
+                Some(pdf) => power_heuristic(pdf, lights.pdf(&ray, &hit_record)),
+                None => 1.,
+            };
+            let emitted = material.emitted(hit_record.uv, hit_record.pointing_at);
+            radiance += throughput * emitted * weight;
+        }
+        if depth == MAX_DEPTH {
+            break;
+        }
+        let scatter = match material.scatter(&ray, &hit_record, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        let wo = -ray.direction().into_normalized();
+        if !scatter.specular {
+            if let Some(light) = lights.sample(hit_record.pointing_at, ray.time(), sampler) {
+                let pdf = material.pdf(&hit_record, light.direction, wo);
+                if pdf > 0. {
+                    let shadow_ray = Ray::new(hit_record.pointing_at, light.direction, ray.time());
+                    let emitted = direct_light(world, background, &shadow_ray, light.distance);
+                    let bsdf = material.eval(&hit_record, light.direction, wo);
+                    let cosine = hit_record.normal.dot(light.direction).abs();
+                    let weight = power_heuristic(light.pdf, pdf);
+                    radiance += throughput * bsdf * emitted * (cosine * weight / light.pdf);
                 }
             }
-            emitted
         }
-        None => 0f32.into(),
+
+        scattering_pdf = if scatter.specular {
+            None
+        } else {
+            Some(scatter.pdf)
+        };
+        throughput *= scatter.weight(hit_record.normal);
+        if scatter.pdf <= 0. || throughput == Vec3::default() {
+            break;
+        }
+        ray = Ray::new(hit_record.pointing_at, scatter.direction, ray.time());
+    }
+    radiance
+}
+
+/// The light arriving along a shadow ray from a light `distance` away,
+/// or nothing if something is in the way. Lights that are infinitely
+/// far away are part of the background.
+fn direct_light(world: &Hitable, background: &Background, shadow_ray: &Ray, distance: f32) -> Vec3 {
+    if distance.is_infinite() {
+        return match world.hit(shadow_ray, 0.001, f32::MAX) {
+            Some(_) => Vec3::default(),
+            None => background.value(shadow_ray.direction()),
+        };
+    }
+    let tolerance = 1e-3 * distance.max(1.);
+    match world.hit(shadow_ray, 0.001, distance + tolerance) {
+        Some(hit_record) if hit_record.t >= distance - tolerance => hit_record
+            .material
+            .emitted(hit_record.uv, hit_record.pointing_at),
+        _ => Vec3::default(),
+    }
+}
+
+#[inline]
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0. {
+        a / (a + b)
+    } else {
+        0.
     }
 }
@@ -0,0 +1,401 @@
+//! Direct lighting. Every emissive surface in the scene is collected
+//! into a flat list of shapes in world space, which can be sampled to
+//! send shadow rays straight towards the lights instead of waiting for
+//! a random bounce to find them. Environment maps are sampled the same
+//! way.
+
+use crate::background::Environment;
+use crate::sampler::Sampler;
+use crate::transform::Matrix4;
+use crate::{Background, HitRecord, Hitable, Ray, Vec3};
+use std::f32::consts::PI;
+
+/// The surface of something that emits light, in world space
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Sphere {
+        start: (f32, Vec3),
+        end: (f32, Vec3),
+        radius: f32,
+    },
+    /// The parallelogram spanned by two edges that meet at `corner`
+    Parallelogram {
+        corner: Vec3,
+        edges: (Vec3, Vec3),
+    },
+    Triangle([Vec3; 3]),
+}
+
+impl Shape {
+    pub fn sphere(center: Vec3, radius: f32) -> Shape {
+        Shape::Sphere {
+            start: (0., center),
+            end: (1., center),
+            radius,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        match self {
+            Shape::Sphere { radius, .. } => 4. * PI * radius * radius,
+            Shape::Parallelogram { edges, .. } => edges.0.cross(edges.1).length(),
+            Shape::Triangle([p0, p1, p2]) => (*p1 - *p0).cross(*p2 - *p0).length() / 2.,
+        }
+    }
+
+    /// Picks a point on the surface, with every point equally likely.
+    /// `u` and `v` are uniformly distributed in [0, 1). Returns the
+    /// point and the surface normal there.
+    pub fn sample(&self, (u, v): (f32, f32), time: f32) -> (Vec3, Vec3) {
+        match self {
+            Shape::Sphere { start, end, radius } => {
+                let center = start.1 + (end.1 - start.1) * ((time - start.0) / (end.0 - start.0));
+                let z = 1. - 2. * u;
+                let r = (1. - z * z).max(0.).sqrt();
+                let phi = 2. * PI * v;
+                let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                (center + normal * *radius, normal)
+            }
+            Shape::Parallelogram { corner, edges } => (
+                *corner + edges.0 * u + edges.1 * v,
+                edges.0.cross(edges.1).into_normalized(),
+            ),
+            Shape::Triangle([p0, p1, p2]) => {
+                let su = u.sqrt();
+                let (b1, b2) = (1. - su, v * su);
+                (
+                    *p0 + (*p1 - *p0) * b1 + (*p2 - *p0) * b2,
+                    (*p1 - *p0).cross(*p2 - *p0).into_normalized(),
+                )
+            }
+        }
+    }
+
+    /// Moves the shape by `matrix`. Spheres only stay spheres when the
+    /// matrix scales every axis by the same amount, so this returns
+    /// `None` for spheres that would be squashed.
+    pub fn transformed(&self, matrix: &Matrix4) -> Option<Shape> {
+        Some(match self {
+            Shape::Sphere { start, end, radius } => Shape::Sphere {
+                start: (start.0, matrix.transform_point(start.1)),
+                end: (end.0, matrix.transform_point(end.1)),
+                radius: radius * matrix.uniform_scale()?,
+            },
+            Shape::Parallelogram { corner, edges } => Shape::Parallelogram {
+                corner: matrix.transform_point(*corner),
+                edges: (
+                    matrix.transform_vector(edges.0),
+                    matrix.transform_vector(edges.1),
+                ),
+            },
+            Shape::Triangle(points) => Shape::Triangle([
+                matrix.transform_point(points[0]),
+                matrix.transform_point(points[1]),
+                matrix.transform_point(points[2]),
+            ]),
+        })
+    }
+}
+
+/// Whether `Lights` can pick points on the surface that a ray hit. If
+/// it can't, light sampling would never have found the light that the
+/// ray did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Points anywhere on the surface can be picked
+    Always,
+    /// The surface is a sphere, which can only be sampled as long as
+    /// it hasn't been scaled unevenly
+    UnlessSquashed,
+    Never,
+}
+
+impl Sampling {
+    /// How a surface is sampled once it's been moved by a matrix,
+    /// `uniform` being whether the matrix scales every axis the same
+    pub(crate) fn transformed(self, uniform: bool) -> Sampling {
+        match self {
+            Sampling::UnlessSquashed if !uniform => Sampling::Never,
+            sampling => sampling,
+        }
+    }
+}
+
+/// Moves `shape` into world space and adds it to `lights`
+pub(crate) fn add_emitter(lights: &mut Vec<Shape>, shape: Shape, matrix: &Matrix4) {
+    match shape.transformed(matrix) {
+        Some(shape) => lights.push(shape),
+        None => {
+            log::warn!("an emissive sphere is scaled unevenly, so it can't be sampled as a light")
+        }
+    }
+}
+
+/// A direction to send a shadow ray in
+#[derive(Debug, Clone)]
+pub struct LightSample {
+    pub direction: Vec3,
+    /// How far away the sampled point is along `direction`. This is
+    /// infinite for the environment.
+    pub distance: f32,
+    /// The probability density of picking `direction`, per unit solid
+    /// angle
+    pub pdf: f32,
+}
+
+/// All of the emissive surfaces in a scene, and the environment if
+/// there is one. Lights are picked in proportion to their area, so
+/// every point on every light is equally likely to be sampled. When
+/// there's an environment as well, it's sampled half of the time.
+#[derive(Debug, Clone, Default)]
+pub struct Lights {
+    shapes: Vec<Shape>,
+    /// The running total of the shapes' areas
+    cdf: Vec<f32>,
+    total_area: f32,
+    environment: Option<Environment>,
+}
+
+impl Lights {
+    /// Finds every emissive surface in `world`, and picks up the
+    /// environment map from `background`
+    pub fn new(world: &Hitable, background: &Background) -> Lights {
+        let mut shapes = Vec::new();
+        world.emitters(&Matrix4::identity(), &mut shapes);
+        let mut total_area = 0.;
+        let cdf = shapes
+            .iter()
+            .map(|shape| {
+                total_area += shape.area();
+                total_area
+            })
+            .collect();
+        let environment = match background {
+            Background::Environment(environment) => Some(environment.clone()),
+            Background::Constant { .. } | Background::Gradient { .. } => None,
+        };
+        Lights {
+            shapes,
+            cdf,
+            total_area,
+            environment,
+        }
+    }
+
+    /// How many lights there are, counting the environment as one
+    pub fn len(&self) -> usize {
+        self.shapes.len() + self.environment.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.has_shapes() && self.environment.is_none()
+    }
+
+    fn has_shapes(&self) -> bool {
+        self.total_area > 0.
+    }
+
+    /// The chance of sampling the environment rather than a shape
+    fn environment_probability(&self) -> f32 {
+        match (&self.environment, self.has_shapes()) {
+            (Some(_), true) => 0.5,
+            (Some(_), false) => 1.,
+            (None, _) => 0.,
+        }
+    }
+
+    /// Picks a point on one of the lights, or a direction towards the
+    /// environment, to send a shadow ray from `origin` towards
+    pub fn sample(&self, origin: Vec3, time: f32, sampler: &mut Sampler) -> Option<LightSample> {
+        let environment_probability = self.environment_probability();
+        let choice = sampler.get_1d();
+        let point = sampler.get_2d();
+        if let Some(environment) = &self.environment {
+            if choice < environment_probability {
+                let (direction, pdf) = environment.sample(point);
+                if pdf <= 0. {
+                    return None;
+                }
+                return Some(LightSample {
+                    direction,
+                    distance: f32::INFINITY,
+                    pdf: pdf * environment_probability,
+                });
+            }
+        }
+        if !self.has_shapes() {
+            return None;
+        }
+        // Stretch what's left of the choice back out to [0, 1)
+        let choice = (choice - environment_probability) / (1. - environment_probability);
+        let target = choice * self.total_area;
+        let index = self
+            .cdf
+            .iter()
+            .position(|&area| area > target)
+            .unwrap_or(self.shapes.len() - 1);
+        let (point, normal) = self.shapes[index].sample(point, time);
+
+        let to_light = point - origin;
+        let distance = to_light.length();
+        if distance <= 0. {
+            return None;
+        }
+        let direction = to_light / distance;
+        let pdf =
+            self.solid_angle_pdf(distance, direction, normal) * (1. - environment_probability);
+        if pdf <= 0. {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            pdf,
+        })
+    }
+
+    /// The probability density, per unit solid angle, that `sample`
+    /// would have picked the direction of `ray`, given that the ray
+    /// went on to hit a light at `hit_record`. Lights are sampled by
+    /// their actual surface, so this uses the geometric normal rather
+    /// than the shading normal.
+    pub fn pdf(&self, ray: &Ray, hit_record: &HitRecord) -> f32 {
+        if !self.has_shapes() || hit_record.sampling == Sampling::Never {
+            return 0.;
+        }
+        let length = ray.direction().length();
+        let pdf = self.solid_angle_pdf(
+            hit_record.t * length,
+            ray.direction() / length,
+            hit_record.geometric_normal,
+        );
+        pdf * (1. - self.environment_probability())
+    }
+
+    /// The probability density, per unit solid angle, that `sample`
+    /// would have picked `direction` towards the environment
+    pub fn environment_pdf(&self, direction: Vec3) -> f32 {
+        match &self.environment {
+            Some(environment) => environment.pdf(direction) * self.environment_probability(),
+            None => 0.,
+        }
+    }
+
+    fn solid_angle_pdf(&self, distance: f32, direction: Vec3, normal: Vec3) -> f32 {
+        let cosine = direction.dot(normal).abs();
+        if cosine < 1e-6 {
+            return 0.;
+        }
+        distance * distance / (cosine * self.total_area)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Diffuse, Lambertian};
+    use crate::rect::XZRect;
+    use crate::sampler::SamplerKind;
+    use crate::texture::Color;
+    use crate::transform::Transform;
+    use crate::triangle::TriangleMesh;
+    use crate::{NormalFlipper, StaticSphere};
+
+    #[test]
+    fn test_finds_transformed_emitters() {
+        let light = Hitable::from(NormalFlipper::new(XZRect::new(
+            -1.,
+            1.,
+            -1.,
+            1.,
+            0.,
+            Diffuse::new(Color::new(4.)),
+        )));
+        let moved = Transform::new(Matrix4::translate(Vec3::new(0., 5., 0.)), light).unwrap();
+        let world = Hitable::List(
+            vec![
+                StaticSphere::new(1., (0., 0., 0.), Lambertian::new(Color::new(0.5))).into(),
+                moved.into(),
+            ]
+            .into(),
+        );
+        let lights = Lights::new(&world, &Background::default());
+        assert_eq!(lights.len(), 1);
+
+        let origin = Vec3::new(0., 2., 0.);
+        let sample = lights
+            .sample(
+                origin,
+                0.,
+                &mut Sampler::new(SamplerKind::Independent, 0, (0, 0), 1),
+            )
+            .unwrap();
+        let ray = Ray::new(origin, sample.direction, 0.);
+        let hit = world.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.t - sample.distance).abs() < 1e-3);
+        assert!((lights.pdf(&ray, &hit) - sample.pdf).abs() < 1e-3 * sample.pdf);
+
+        // Smooth meshes shade with normals that lean away from the
+        // triangle, but the light is sampled on the triangle itself
+        let mesh = Hitable::from(TriangleMesh::new(
+            vec![
+                Vec3::new(-1., 5., -1.),
+                Vec3::new(1., 5., -1.),
+                Vec3::new(0., 5., 1.),
+            ],
+            vec![
+                Vec3::new(2., -1., 0.),
+                Vec3::new(-2., -1., 1.),
+                Vec3::new(0., -1., -2.),
+            ],
+            Vec::new(),
+            vec![[0, 1, 2]],
+            Diffuse::new(Color::new(4.)),
+        ));
+        let lights = Lights::new(&mesh, &Background::default());
+        for seed in 0..8 {
+            let sample = lights
+                .sample(
+                    origin,
+                    0.,
+                    &mut Sampler::new(SamplerKind::Independent, seed, (0, 0), 1),
+                )
+                .unwrap();
+            let ray = Ray::new(origin, sample.direction, 0.);
+            let hit = mesh.hit(&ray, 0.001, f32::MAX).unwrap();
+            assert!(hit.normal.dot(hit.geometric_normal) < 0.99);
+            assert!((lights.pdf(&ray, &hit) - sample.pdf).abs() < 1e-3 * sample.pdf);
+        }
+    }
+
+    /// A sphere that's been squashed can't be sampled, so rays that hit
+    /// it have to bring back all of its light themselves
+    #[test]
+    fn test_unsampled_emitters_have_no_light_pdf() {
+        let sphere = |scale: Vec3, x: f32| -> Hitable {
+            let sphere = StaticSphere::new(1., (0., 0., 0.), Diffuse::new(Color::new(4.)));
+            let matrix = Matrix4::translate(Vec3::new(x, 0., 0.)) * Matrix4::scale(scale);
+            Transform::new(matrix, Hitable::from(sphere))
+                .unwrap()
+                .into()
+        };
+        let world = Hitable::List(
+            vec![
+                sphere(Vec3::new(2., 2., 2.), -5.),
+                sphere(Vec3::new(1., 2., 1.), 5.),
+            ]
+            .into(),
+        );
+        let lights = Lights::new(&world, &Background::default());
+        assert_eq!(lights.len(), 1);
+
+        let origin = Vec3::new(0., 0., 10.);
+        let towards = |x: f32| Ray::new(origin, Vec3::new(x, 0., 0.) - origin, 0.);
+        let round = world.hit(&towards(-5.), 0.001, f32::MAX).unwrap();
+        assert_eq!(round.sampling, Sampling::UnlessSquashed);
+        assert!(lights.pdf(&towards(-5.), &round) > 0.);
+        let squashed = world.hit(&towards(5.), 0.001, f32::MAX).unwrap();
+        assert_eq!(squashed.sampling, Sampling::Never);
+        assert_eq!(lights.pdf(&towards(5.), &squashed), 0.);
+    }
+}
@@ -0,0 +1,279 @@
+//! The files a scene refers to are loaded while it's being
+//! deserialized. Loading a scene sets up a context for that, which
+//! finds the files relative to the scene file and keeps track of what's
+//! already been loaded, so nothing is shared between different scenes.
+//! It also notes every file that's read, since changing any of them
+//! changes the scene.
+//!
+//! A scene can also be loaded from the contents of its files, sent
+//! along with it, on a machine that doesn't have the files itself.
+
+use crate::transform::Placement;
+use crate::Hitable;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The contents of the files a scene reads, by the paths the scene
+/// refers to them by. Each file is serialized as base64.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Files(#[serde(with = "encoded_map")] BTreeMap<String, Vec<u8>>);
+
+impl Files {
+    /// Reads every file in `paths`, such as the files a scene was
+    /// loaded from
+    pub fn read(paths: &[PathBuf]) -> io::Result<Files> {
+        let mut files = BTreeMap::new();
+        for path in paths {
+            let contents = fs::read(path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("couldn't read {}: {}", path.display(), err),
+                )
+            })?;
+            files.insert(path.to_string_lossy().into_owned(), contents);
+        }
+        Ok(Files(files))
+    }
+}
+
+#[derive(Default)]
+struct Context {
+    /// The directory of the scene file
+    directory: PathBuf,
+    /// Models that have already been loaded, by path and placement
+    models: Vec<(PathBuf, Placement, Arc<Hitable>)>,
+    /// The scene's instances that have been defined so far, by name
+    instances: HashMap<String, Arc<Hitable>>,
+    /// Every file that's been read, in the order they were first read
+    files: Vec<PathBuf>,
+    /// What to read files from instead of the disk, if anything
+    contents: Option<Arc<Files>>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Puts back whichever context was there before, even if loading
+/// panics
+struct Restore(Option<Context>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+/// Runs `load` in `context`, returning the context as `load` left it
+fn with_context<T>(context: Context, load: impl FnOnce() -> T) -> (T, Context) {
+    let previous = CONTEXT.with(|current| current.borrow_mut().replace(context));
+    let _restore = Restore(previous);
+    let result = load();
+    let context = CONTEXT.with(|current| current.borrow_mut().take());
+    (result, context.unwrap_or_default())
+}
+
+/// Runs `load`, finding the files it refers to relative to
+/// `directory`
+pub fn relative_to<T>(directory: &Path, load: impl FnOnce() -> T) -> T {
+    let context = Context {
+        directory: directory.to_owned(),
+        ..Context::default()
+    };
+    with_context(context, load).0
+}
+
+/// Runs `load`, reading files from `files` instead of the disk. Files
+/// that aren't in `files` can't be read at all, so nothing depends on
+/// what happens to be on this machine.
+pub fn from_files<T>(files: Files, load: impl FnOnce() -> T) -> T {
+    let context = Context {
+        contents: Some(Arc::new(files)),
+        ..Context::default()
+    };
+    with_context(context, load).0
+}
+
+/// Runs `load` for a single scene, which doesn't share anything with
+/// any other scene. Files are found relative to the directory of the
+/// scene being loaded, if there is one, or the working directory.
+/// Returns what `load` did along with every file it read.
+pub(crate) fn scene<T>(load: impl FnOnce() -> T) -> (T, Vec<PathBuf>) {
+    let (directory, contents) = CONTEXT.with(|context| match context.borrow().as_ref() {
+        Some(context) => (context.directory.clone(), context.contents.clone()),
+        None => (PathBuf::new(), None),
+    });
+    let context = Context {
+        directory,
+        contents,
+        ..Context::default()
+    };
+    let (result, context) = with_context(context, load);
+    (result, context.files)
+}
+
+/// Where the file at `path`, as written in the scene, actually is
+pub(crate) fn resolve(path: &str) -> PathBuf {
+    CONTEXT.with(|context| match context.borrow().as_ref() {
+        Some(context) => context.directory.join(path),
+        None => PathBuf::from(path),
+    })
+}
+
+/// Reads the whole file at `path`, from the files the scene was sent
+/// with if there are any
+pub(crate) fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let contents = CONTEXT.with(|context| {
+        let context = context.borrow();
+        let files = context.as_ref()?.contents.as_ref()?;
+        Some(files.0.get(path.to_string_lossy().as_ref()).cloned())
+    });
+    let contents = match contents {
+        Some(Some(contents)) => contents,
+        Some(None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} wasn't sent with the scene", path.display()),
+            ))
+        }
+        None => fs::read(path)?,
+    };
+    record(path);
+    Ok(contents)
+}
+
+/// Reads the image at `path`. Most formats are recognized by their
+/// contents, but TGA files have to be named as such.
+pub(crate) fn image(path: &Path) -> ImageResult<DynamicImage> {
+    let contents = read(path)?;
+    let is_tga = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("tga"));
+    match image::guess_format(&contents) {
+        Err(_) if is_tga => image::load_from_memory_with_format(&contents, ImageFormat::TGA),
+        format => image::load_from_memory_with_format(&contents, format?),
+    }
+}
+
+/// Notes that the file at `path` has been read
+fn record(path: &Path) {
+    CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut() {
+            if !context.files.iter().any(|file| file == path) {
+                context.files.push(path.to_owned());
+            }
+        }
+    });
+}
+
+/// The model at `path` with the given placement, loading it with
+/// `load` unless the scene already has
+pub(crate) fn model<E>(
+    path: &Path,
+    placement: &Placement,
+    load: impl FnOnce() -> Result<Hitable, E>,
+) -> Result<Arc<Hitable>, E> {
+    let existing = CONTEXT.with(|context| {
+        context.borrow().as_ref().and_then(|context| {
+            context
+                .models
+                .iter()
+                .find(|(loaded, loaded_placement, _)| {
+                    loaded == path && loaded_placement == placement
+                })
+                .map(|(_, _, objects)| Arc::clone(objects))
+        })
+    });
+    if let Some(objects) = existing {
+        return Ok(objects);
+    }
+
+    // Nothing's borrowed while loading, in case the model has to look
+    // anything up
+    let objects = Arc::new(load()?);
+    CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut() {
+            context
+                .models
+                .push((path.to_owned(), placement.clone(), Arc::clone(&objects)));
+        }
+    });
+    Ok(objects)
+}
+
+/// Makes `instance` available to the rest of the scene by `name`.
+/// Returns false if there's already an instance with that name.
+pub(crate) fn define_instance(name: &str, instance: &Arc<Hitable>) -> bool {
+    CONTEXT.with(|context| match context.borrow_mut().as_mut() {
+        Some(context) if !context.instances.contains_key(name) => {
+            context
+                .instances
+                .insert(name.to_owned(), Arc::clone(instance));
+            true
+        }
+        _ => false,
+    })
+}
+
+/// The instance called `name`, if it's been defined
+pub(crate) fn instance(name: &str) -> Option<Arc<Hitable>> {
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        context.as_ref()?.instances.get(name).cloned()
+    })
+}
+
+/// Serializes bytes as base64, for `#[serde(with = "...")]`
+pub(crate) mod encoded {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        use serde::de::Error;
+        let encoded: String = serde::Deserialize::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Serializes a map of bytes as a map of base64
+mod encoded_map {
+    use super::*;
+    use serde::ser::SerializeMap;
+
+    pub fn serialize<S: Serializer>(
+        files: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(files.len()))?;
+        for (path, contents) in files {
+            map.serialize_entry(path, &STANDARD.encode(contents))?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        use serde::de::Error;
+        let encoded: BTreeMap<String, String> = serde::Deserialize::deserialize(deserializer)?;
+        encoded
+            .into_iter()
+            .map(|(path, contents)| {
+                Ok((path, STANDARD.decode(contents).map_err(D::Error::custom)?))
+            })
+            .collect()
+    }
+}
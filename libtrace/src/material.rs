@@ -1,10 +1,37 @@
+use crate::sampler::Sampler;
 use crate::texture::Texture;
 use crate::{HitRecord, Ray, Vec3};
 use serde_derive::{Deserialize, Serialize};
+use std::f32::consts::PI;
 
+/// A direction picked by `Material::scatter` for the path to continue in
+#[derive(Debug, Clone)]
 pub struct Scatter {
-    pub attenuation: Vec3,
-    pub scatter: Ray,
+    /// The normalized direction that light arrives from
+    pub direction: Vec3,
+    /// The value of the BSDF for this direction. For specular scatters
+    /// this is just the fraction of light that's reflected, as there
+    /// was only ever one direction to pick.
+    pub bsdf: Vec3,
+    /// The probability density, per unit solid angle, of picking
+    /// `direction`. This is 1 for specular scatters.
+    pub pdf: f32,
+    /// Whether the material only scatters in one direction (give or
+    /// take some fuzz), so `eval` and `pdf` can't be used for it
+    pub specular: bool,
+}
+
+impl Scatter {
+    /// How much of the light arriving from `direction` carries on along
+    /// the path, for a surface with the given normal
+    #[inline]
+    pub fn weight(&self, normal: Vec3) -> Vec3 {
+        if self.specular {
+            self.bsdf
+        } else {
+            self.bsdf * (normal.dot(self.direction).abs() / self.pdf)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -17,16 +44,50 @@ pub enum Material {
 }
 
 impl Material {
+    /// Picks a direction for a path that arrived along `ray` to carry on
+    /// in. Materials that only emit light don't scatter.
     #[inline]
-    pub fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scatter> {
+    pub fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        sampler: &mut Sampler,
+    ) -> Option<Scatter> {
         match self {
-            Material::Lambertian(l) => l.scatter(ray, hit_record),
-            Material::Metal(m) => m.scatter(ray, hit_record),
-            Material::Dialectric(d) => d.scatter(ray, hit_record),
+            Material::Lambertian(l) => l.scatter(ray, hit_record, sampler),
+            Material::Metal(m) => m.scatter(ray, hit_record, sampler),
+            Material::Dialectric(d) => d.scatter(ray, hit_record, sampler),
             Material::Diffuse(diff) => diff.scatter(ray, hit_record),
         }
     }
 
+    /// The BSDF for light arriving from `wi` and leaving towards `wo`.
+    /// Both directions point away from the surface. Specular materials
+    /// are zero everywhere, as the chance of picking their one direction
+    /// some other way is zero.
+    pub fn eval(&self, hit_record: &HitRecord, wi: Vec3, wo: Vec3) -> Vec3 {
+        match self {
+            Material::Lambertian(l) => l.eval(hit_record, wi, wo),
+            Material::Metal(_) | Material::Dialectric(_) | Material::Diffuse(_) => 0f32.into(),
+        }
+    }
+
+    /// The probability density, per unit solid angle, that `scatter`
+    /// picks `wi` for a ray leaving towards `wo`
+    pub fn pdf(&self, hit_record: &HitRecord, wi: Vec3, wo: Vec3) -> f32 {
+        match self {
+            Material::Lambertian(l) => l.pdf(hit_record, wi, wo),
+            Material::Metal(_) | Material::Dialectric(_) | Material::Diffuse(_) => 0.,
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Material::Diffuse(_) => true,
+            Material::Lambertian(_) | Material::Metal(_) | Material::Dialectric(_) => false,
+        }
+    }
+
     pub fn emitted(&self, uv: (f32, f32), p: Vec3) -> Vec3 {
         match self {
             Material::Diffuse(d) => d.emitted(uv, p),
@@ -73,18 +134,28 @@ impl Lambertian {
             albedo: albedo.into(),
         }
     }
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scatter> {
-        let target = hit_record.normal + Vec3::random_in_unit_circle();
-        let scattered = Ray::new(hit_record.pointing_at, target, ray.time());
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut Sampler) -> Option<Scatter> {
+        let direction = random_cosine_direction(hit_record.normal, sampler.get_2d());
+        let wo = -ray.direction().into_normalized();
         Some(Scatter {
-            attenuation: self.albedo.value(
-                hit_record.uv.0,
-                hit_record.uv.1,
-                hit_record.pointing_at,
-            ),
-            scatter: scattered,
+            direction,
+            bsdf: self.eval(hit_record, direction, wo),
+            pdf: self.pdf(hit_record, direction, wo),
+            specular: false,
         })
     }
+
+    fn eval(&self, hit_record: &HitRecord, wi: Vec3, _wo: Vec3) -> Vec3 {
+        if hit_record.normal.dot(wi) <= 0. {
+            return 0f32.into();
+        }
+        let (u, v) = hit_record.uv;
+        self.albedo.value(u, v, hit_record.pointing_at) / PI
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: Vec3, _wo: Vec3) -> f32 {
+        hit_record.normal.dot(wi).max(0.) / PI
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -101,23 +172,37 @@ impl Metal {
             fuzz,
         }
     }
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut Sampler) -> Option<Scatter> {
         let reflected = reflect(ray.direction().into_normalized(), hit_record.normal);
         Some(Scatter {
-            scatter: Ray::new(
-                hit_record.pointing_at,
-                reflected + Vec3::random_in_unit_circle() * self.fuzz,
-                ray.time(),
-            ),
-            attenuation: self.albedo.value(
-                hit_record.uv.0,
-                hit_record.uv.1,
-                hit_record.pointing_at,
-            ),
+            direction: (reflected + Vec3::random_in_unit_circle(sampler.rng()) * self.fuzz)
+                .into_normalized(),
+            bsdf: self
+                .albedo
+                .value(hit_record.uv.0, hit_record.uv.1, hit_record.pointing_at),
+            pdf: 1.,
+            specular: true,
         })
     }
 }
 
+/// Picks a direction on the hemisphere around `normal`, favouring
+/// directions close to the normal in proportion to the cosine of the
+/// angle between them
+fn random_cosine_direction(normal: Vec3, (r1, r2): (f32, f32)) -> Vec3 {
+    let phi = 2. * PI * r1;
+    let r = r2.sqrt();
+
+    let helper = if normal.x().abs() > 0.9 {
+        Vec3::new(0., 1., 0.)
+    } else {
+        Vec3::new(1., 0., 0.)
+    };
+    let bitangent = normal.cross(helper).into_normalized();
+    let tangent = bitangent.cross(normal);
+    tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1. - r2).sqrt()
+}
+
 #[inline]
 fn reflect(vec: Vec3, norm: Vec3) -> Vec3 {
     vec - norm * 2. * vec.dot(norm)
@@ -132,7 +217,7 @@ impl Dialectric {
     pub fn new(ref_idx: f32) -> Dialectric {
         Dialectric { ref_idx }
     }
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut Sampler) -> Option<Scatter> {
         let (outward_normal, ni_over_nt, cosine) = if ray.direction().dot(hit_record.normal) > 0. {
             let cosine =
                 self.ref_idx * ray.direction().dot(hit_record.normal) / ray.direction().length();
@@ -149,20 +234,22 @@ impl Dialectric {
             None => (1.0, None),
         };
         let reflected = reflect(ray.direction(), hit_record.normal);
-        let scatter = match refracted {
+        let direction = match refracted {
             Some(refracted) => {
-                if rand::random::<f32>() < reflect_prob {
-                    Ray::new(hit_record.pointing_at, reflected, ray.time())
+                if sampler.get_1d() < reflect_prob {
+                    reflected
                 } else {
-                    Ray::new(hit_record.pointing_at, refracted, ray.time())
+                    refracted
                 }
             }
-            None => Ray::new(hit_record.pointing_at, reflected, ray.time()),
+            None => reflected,
         };
 
         Some(Scatter {
-            scatter,
-            attenuation: Vec3::new(1., 1., 1.),
+            direction: direction.into_normalized(),
+            bsdf: Vec3::new(1., 1., 1.),
+            pdf: 1.,
+            specular: true,
         })
     }
 }
@@ -176,7 +263,7 @@ impl Diffuse {
     pub fn new<T: Into<Texture>>(emit: T) -> Diffuse {
         Diffuse { emit: emit.into() }
     }
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scatter> {
+    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<Scatter> {
         None
     }
     fn emitted(&self, (u, v): (f32, f32), p: Vec3) -> Vec3 {
@@ -201,3 +288,36 @@ fn refract(vec: &Vec3, norm: &Vec3, ni_over_nt: f32) -> Option<Vec3> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::Sampling;
+    use crate::sampler::SamplerKind;
+    use crate::texture::Color;
+
+    #[test]
+    fn test_lambertian_weight_is_albedo() {
+        let material = Material::from(Lambertian::new(Color::new(0.5)));
+        let hit_record = HitRecord {
+            t: 1.,
+            pointing_at: Vec3::new(0., 0., 0.),
+            normal: Vec3::new(0., 1., 0.),
+            geometric_normal: Vec3::new(0., 1., 0.),
+            sampling: Sampling::Always,
+            uv: (0., 0.),
+            material: &material,
+        };
+        let ray = Ray::new(Vec3::new(1., 1., 0.), Vec3::new(-1., -1., 0.), 0.);
+        let wo = -ray.direction().into_normalized();
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, (0, 0), 100);
+        for sample in 0..100 {
+            sampler.start_sample(sample);
+            let scatter = material.scatter(&ray, &hit_record, &mut sampler).unwrap();
+            assert!(!scatter.specular);
+            assert!((scatter.pdf - material.pdf(&hit_record, scatter.direction, wo)).abs() < 1e-5);
+            let weight = scatter.weight(hit_record.normal);
+            assert!((weight - Vec3::new(0.5, 0.5, 0.5)).length() < 1e-4);
+        }
+    }
+}
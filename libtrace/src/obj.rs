@@ -0,0 +1,675 @@
+//! Loading Wavefront OBJ models, along with the MTL files they
+//! reference, into triangle meshes.
+//!
+//! Every combination of group and material in the file becomes its own
+//! `TriangleMesh`. Faces without explicit normals that are part of a
+//! smoothing group get normals averaged from the faces around them.
+//!
+//! Models that are placed with the same transform and don't override
+//! their material are only loaded once, however many times they appear
+//! in a scene. To put copies of a model in different places, wrap it in
+//! a `Transform`. Scene files refer to models relative to where the
+//! scene file is.
+
+use crate::aabb::Aabb;
+use crate::bvh::BvhOptions;
+use crate::light::Shape;
+use crate::material::{Dialectric, Diffuse, Lambertian, Metal};
+use crate::texture::{self, Color, Texture};
+use crate::transform::{Matrix4, Placement};
+use crate::triangle::TriangleMesh;
+use crate::{load, HitRecord, Hitable, Material, Ray, Vec3};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    Texture(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            ObjError::Texture(path, err) => {
+                write!(f, "could not load texture {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl Error for ObjError {}
+
+/// A material as described by an MTL file
+#[derive(Debug, Clone)]
+struct MtlMaterial {
+    diffuse: Vec3,
+    specular: Vec3,
+    emissive: Vec3,
+    shininess: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32,
+    diffuse_map: Option<PathBuf>,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> MtlMaterial {
+        MtlMaterial {
+            diffuse: Vec3::from(0.8),
+            specular: Vec3::default(),
+            emissive: Vec3::default(),
+            shininess: 0.,
+            ior: 1.,
+            dissolve: 1.,
+            illum: 2,
+            diffuse_map: None,
+        }
+    }
+}
+
+impl MtlMaterial {
+    /// Picks whichever of our materials is closest to the MTL
+    /// description:
+    ///
+    /// - anything with a nonzero `Ke` is a light
+    /// - transparent materials (`d < 1`, or `illum` 4, 6, 7 or 9) are
+    ///   dielectrics with an index of refraction of `Ni`
+    /// - materials with `illum 3`, or that are more specular than
+    ///   diffuse, are metals. `Ns` is converted to the fuzz of the metal
+    /// - everything else is lambertian, textured by `map_Kd` if present
+    fn to_material(&self) -> Result<Material, ObjError> {
+        if self.emissive.luminance() > 0. {
+            return Ok(Diffuse::new(Color::new(self.emissive)).into());
+        }
+        if self.dissolve < 1. || [4, 6, 7, 9].contains(&self.illum) {
+            return Ok(Dialectric::new(self.ior).into());
+        }
+        if self.illum == 3 || self.specular.luminance() > self.diffuse.luminance() {
+            let fuzz = (2. / (self.shininess + 2.)).sqrt();
+            return Ok(Metal::new(Color::new(self.specular), fuzz).into());
+        }
+        let albedo: Texture = match &self.diffuse_map {
+            Some(path) => texture::Image::open(path.to_string_lossy())
+                .map_err(|err| ObjError::Texture(path.clone(), err))?
+                .into(),
+            None => Color::new(self.diffuse).into(),
+        };
+        Ok(Lambertian::new(albedo).into())
+    }
+}
+
+fn parse_floats<'a>(args: impl Iterator<Item = &'a str>, min: usize) -> Result<Vec<f32>, String> {
+    let values = args
+        .map(|arg| {
+            arg.parse::<f32>()
+                .map_err(|_| format!("expected a number, found {:?}", arg))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() < min {
+        return Err(format!("expected {} numbers, found {}", min, values.len()));
+    }
+    Ok(values)
+}
+
+fn parse_vec3<'a>(args: impl Iterator<Item = &'a str>) -> Result<Vec3, String> {
+    let values = parse_floats(args, 3)?;
+    Ok(Vec3::new(values[0], values[1], values[2]))
+}
+
+fn read_lines<F>(path: &Path, mut f: F) -> Result<(), ObjError>
+where
+    F: FnMut(&str, std::str::SplitWhitespace) -> Result<(), String>,
+{
+    let contents = load::read(path).map_err(|err| ObjError::Io(path.to_owned(), err))?;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.map_err(|err| ObjError::Io(path.to_owned(), err))?;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => &line[..],
+        };
+        let mut words = line.split_whitespace();
+        if let Some(keyword) = words.next() {
+            f(keyword, words).map_err(|message| ObjError::Parse {
+                path: path.to_owned(),
+                line: number + 1,
+                message,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn load_mtl(path: &Path, materials: &mut HashMap<String, MtlMaterial>) -> Result<(), ObjError> {
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    read_lines(path, |keyword, mut args| {
+        if keyword == "newmtl" {
+            let name = args.collect::<Vec<_>>().join(" ");
+            if let Some((name, material)) = current.replace((name, MtlMaterial::default())) {
+                materials.insert(name, material);
+            }
+            return Ok(());
+        }
+        let material = match &mut current {
+            Some((_, material)) => material,
+            None => return Err(format!("{} appears before any newmtl", keyword)),
+        };
+        match keyword {
+            "Kd" => material.diffuse = parse_vec3(args)?,
+            "Ks" => material.specular = parse_vec3(args)?,
+            "Ke" => material.emissive = parse_vec3(args)?,
+            "Ns" => material.shininess = parse_floats(args, 1)?[0],
+            "Ni" => material.ior = parse_floats(args, 1)?[0],
+            "d" => material.dissolve = parse_floats(args, 1)?[0],
+            "Tr" => material.dissolve = 1. - parse_floats(args, 1)?[0],
+            "illum" => {
+                let illum = args.next().unwrap_or("");
+                material.illum = illum
+                    .parse()
+                    .map_err(|_| format!("invalid illumination model {:?}", illum))?;
+            }
+            "map_Kd" => {
+                // any options come before the file name
+                let file = args.last().ok_or("map_Kd is missing a file name")?;
+                material.diffuse_map = Some(base.join(file));
+            }
+            _ => log::debug!("ignoring unsupported MTL statement {}", keyword),
+        }
+        Ok(())
+    })?;
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+    Ok(())
+}
+
+/// Where the normal for a corner of a face comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NormalSource {
+    /// A `vn` statement in the file
+    Explicit(usize),
+    /// Averaged from the faces around a position in a smoothing group
+    Smoothed { position: usize, group: u32 },
+    /// The normal of the face itself
+    Flat { face: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: NormalSource,
+}
+
+/// The group and material that a face belongs to
+type MeshKey = (String, Option<String>);
+
+#[derive(Debug, Default)]
+struct ObjData {
+    positions: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    normals: Vec<Vec3>,
+    /// Triangles, grouped by the group and material they belong to
+    meshes: Vec<(MeshKey, Vec<[Corner; 3]>)>,
+    num_faces: usize,
+    face_normals: Vec<Vec3>,
+    smoothed_normals: HashMap<(usize, u32), Vec3>,
+}
+
+/// Resolves a 1-based, possibly negative, OBJ index
+fn resolve_index(index: &str, len: usize) -> Result<usize, String> {
+    let value: i64 = index
+        .parse()
+        .map_err(|_| format!("invalid index {:?}", index))?;
+    let resolved = if value < 0 {
+        len as i64 + value
+    } else {
+        value - 1
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(format!("index {} is out of range", value));
+    }
+    Ok(resolved as usize)
+}
+
+impl ObjData {
+    fn parse(
+        path: &Path,
+        materials: &mut HashMap<String, MtlMaterial>,
+    ) -> Result<ObjData, ObjError> {
+        let base = path.parent().unwrap_or_else(|| Path::new("")).to_owned();
+        let mut data = ObjData::default();
+        let mut group = String::from("default");
+        let mut material: Option<String> = None;
+        let mut smoothing_group = 0u32;
+        let mut mtl_files = Vec::new();
+
+        read_lines(path, |keyword, mut args| {
+            match keyword {
+                "v" => data.positions.push(parse_vec3(args)?),
+                "vn" => data.normals.push(parse_vec3(args)?),
+                "vt" => {
+                    let values = parse_floats(args, 1)?;
+                    data.uvs
+                        .push((values[0], values.get(1).cloned().unwrap_or(0.)));
+                }
+                "f" => {
+                    let key = (group.clone(), material.clone());
+                    data.add_face(key, args, smoothing_group)?;
+                }
+                "g" | "o" => {
+                    let name = args.collect::<Vec<_>>().join(" ");
+                    group = if name.is_empty() {
+                        String::from("default")
+                    } else {
+                        name
+                    };
+                }
+                "s" => {
+                    let value = args.next().unwrap_or("off");
+                    smoothing_group = match value {
+                        "off" => 0,
+                        _ => value
+                            .parse()
+                            .map_err(|_| format!("invalid smoothing group {:?}", value))?,
+                    };
+                }
+                "usemtl" => material = Some(args.collect::<Vec<_>>().join(" ")),
+                "mtllib" => mtl_files.extend(args.map(|file| base.join(file))),
+                _ => log::debug!("ignoring unsupported OBJ statement {}", keyword),
+            }
+            Ok(())
+        })?;
+
+        for file in mtl_files {
+            load_mtl(&file, materials)?;
+        }
+        Ok(data)
+    }
+
+    fn add_face<'a>(
+        &mut self,
+        key: MeshKey,
+        args: impl Iterator<Item = &'a str>,
+        smoothing_group: u32,
+    ) -> Result<(), String> {
+        let mut corners = Vec::new();
+        let face = self.num_faces;
+        for vertex in args {
+            let mut indices = vertex.split('/');
+            let position = resolve_index(indices.next().unwrap_or(""), self.positions.len())?;
+            let uv = match indices.next() {
+                Some(uv) if !uv.is_empty() => Some(resolve_index(uv, self.uvs.len())?),
+                _ => None,
+            };
+            let normal = match indices.next() {
+                Some(normal) if !normal.is_empty() => {
+                    NormalSource::Explicit(resolve_index(normal, self.normals.len())?)
+                }
+                _ if smoothing_group != 0 => NormalSource::Smoothed {
+                    position,
+                    group: smoothing_group,
+                },
+                _ => NormalSource::Flat { face },
+            };
+            corners.push(Corner {
+                position,
+                uv,
+                normal,
+            });
+        }
+        if corners.len() < 3 {
+            return Err(format!(
+                "a face needs at least 3 vertices, found {}",
+                corners.len()
+            ));
+        }
+
+        // Cross product of the diagonals, which for planar polygons is
+        // twice the area weighted normal
+        let p = |i: usize| self.positions[corners[i].position];
+        let face_normal = if corners.len() == 3 {
+            (p(1) - p(0)).cross(p(2) - p(0))
+        } else {
+            (p(2) - p(0)).cross(p(3) - p(1))
+        };
+        for corner in &corners {
+            if let NormalSource::Smoothed { position, group } = corner.normal {
+                *self.smoothed_normals.entry((position, group)).or_default() += face_normal;
+            }
+        }
+        self.face_normals.push(face_normal);
+        self.num_faces += 1;
+
+        let triangles = match self.meshes.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, triangles)) => triangles,
+            None => {
+                self.meshes.push((key, Vec::new()));
+                &mut self.meshes.last_mut().unwrap().1
+            }
+        };
+        // polygons are split into a fan around the first vertex
+        for i in 1..corners.len() - 1 {
+            triangles.push([corners[0], corners[i], corners[i + 1]]);
+        }
+        Ok(())
+    }
+
+    fn normal(&self, source: NormalSource) -> Vec3 {
+        let normal = match source {
+            NormalSource::Explicit(index) => self.normals[index],
+            NormalSource::Smoothed { position, group } => self.smoothed_normals[&(position, group)],
+            NormalSource::Flat { face } => self.face_normals[face],
+        };
+        if normal.squared_length() > 0. {
+            normal.into_normalized()
+        } else {
+            normal
+        }
+    }
+
+    fn build_mesh(
+        &self,
+        triangles: &[[Corner; 3]],
+        material: Material,
+        transform: &Matrix4,
+    ) -> TriangleMesh {
+        // a transform that can't be inverted flattens the model, so its
+        // normals don't really matter
+        let inverse = transform.inverse().unwrap_or_default();
+        let has_normals = triangles
+            .iter()
+            .flat_map(|triangle| triangle.iter())
+            .any(|corner| !matches!(corner.normal, NormalSource::Flat { .. }));
+        let has_uvs = triangles
+            .iter()
+            .flat_map(|triangle| triangle.iter())
+            .any(|corner| corner.uv.is_some());
+
+        let mut vertices: HashMap<Corner, u32> = HashMap::new();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut faces = Vec::with_capacity(triangles.len());
+
+        for triangle in triangles {
+            let mut face = [0; 3];
+            for (i, corner) in triangle.iter().enumerate() {
+                let mut corner = *corner;
+                if !has_normals {
+                    // every face shares the same kind of normal, so
+                    // there's no need to split vertices between faces
+                    corner.normal = NormalSource::Flat { face: 0 };
+                }
+                face[i] = *vertices.entry(corner).or_insert_with(|| {
+                    positions.push(transform.transform_point(self.positions[corner.position]));
+                    if has_normals {
+                        let normal = inverse.transform_normal(self.normal(corner.normal));
+                        normals.push(normal.into_normalized());
+                    }
+                    if has_uvs {
+                        uvs.push(corner.uv.map(|uv| self.uvs[uv]).unwrap_or((0., 0.)));
+                    }
+                    positions.len() as u32 - 1
+                });
+            }
+            faces.push(face);
+        }
+
+        TriangleMesh::new(positions, normals, uvs, faces, material)
+    }
+}
+
+/// Loads the OBJ file at `path` as a list of triangle meshes. If
+/// `material` is given it's used for every face, otherwise materials
+/// come from the MTL files that the OBJ file references.
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    transform: &Placement,
+    material: Option<&Material>,
+) -> Result<Vec<TriangleMesh>, ObjError> {
+    let transform = transform.matrix();
+    let mut mtl_materials = HashMap::new();
+    let data = ObjData::parse(path.as_ref(), &mut mtl_materials)?;
+
+    let mut materials: HashMap<Option<String>, Material> = HashMap::new();
+    let mut meshes = Vec::with_capacity(data.meshes.len());
+    for ((_, material_name), triangles) in &data.meshes {
+        let material = match material {
+            Some(material) => material.clone(),
+            None => match materials.get(material_name) {
+                Some(material) => material.clone(),
+                None => {
+                    let mtl = match material_name {
+                        Some(name) => mtl_materials.get(name).cloned().unwrap_or_else(|| {
+                            log::warn!("material {} was not found, using the default", name);
+                            MtlMaterial::default()
+                        }),
+                        None => MtlMaterial::default(),
+                    };
+                    let converted = mtl.to_material()?;
+                    materials.insert(material_name.clone(), converted.clone());
+                    converted
+                }
+            },
+        };
+        meshes.push(data.build_mesh(triangles, material, &transform));
+    }
+    Ok(meshes)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ModelDescription {
+    path: String,
+    #[serde(default)]
+    transform: Placement,
+    #[serde(default)]
+    material: Option<Material>,
+}
+
+/// An OBJ model in a scene. Scene files just reference the model by
+/// path, and the model is loaded when the scene is.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "ModelDescription", into = "ModelDescription")]
+pub struct Model {
+    description: ModelDescription,
+    objects: Arc<Hitable>,
+}
+
+fn load_objects(description: &ModelDescription) -> Result<Hitable, ObjError> {
+    let meshes = load(
+        &description.path,
+        &description.transform,
+        description.material.as_ref(),
+    )?;
+    let meshes: Vec<Hitable> = meshes.into_iter().map(Hitable::from).collect();
+    Ok(Hitable::List(meshes.into()).into_bvh((0., 1.), &BvhOptions::default()))
+}
+
+impl TryFrom<ModelDescription> for Model {
+    type Error = ObjError;
+
+    fn try_from(mut description: ModelDescription) -> Result<Model, ObjError> {
+        // From here on the model is wherever it was found, so the scene
+        // can be passed on without the scene file
+        let path = load::resolve(&description.path);
+        description.path = path.to_string_lossy().into_owned();
+        let objects = if description.material.is_some() {
+            Arc::new(load_objects(&description)?)
+        } else {
+            load::model(&path, &description.transform, || load_objects(&description))?
+        };
+        Ok(Model {
+            description,
+            objects,
+        })
+    }
+}
+
+impl From<Model> for ModelDescription {
+    fn from(model: Model) -> ModelDescription {
+        model.description
+    }
+}
+
+impl Model {
+    /// Loads the model at `path`
+    pub fn open<P: Into<String>>(
+        path: P,
+        transform: Placement,
+        material: Option<Material>,
+    ) -> Result<Model, ObjError> {
+        Model::try_from(ModelDescription {
+            path: path.into(),
+            transform,
+            material,
+        })
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.objects.hit(ray, t_min, t_max)
+    }
+
+    pub fn bounding_box(&self, time: (f32, f32)) -> Aabb {
+        self.objects.bounding_box(time)
+    }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        self.objects.emitters(matrix, lights);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_model(name: &str, obj: &str, mtl: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("libtrace-obj-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("model.mtl"), mtl).unwrap();
+        let path = dir.join("model.obj");
+        fs::write(&path, obj).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_groups_and_materials() {
+        let path = write_model(
+            "groups",
+            "mtllib model.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+g quad
+usemtl red
+f 1/1 2/2 3/3 4/4
+g light
+usemtl lamp
+f -4 -2 -1
+",
+            "newmtl red
+Kd 0.8 0.1 0.1
+newmtl lamp
+Ke 4 4 4
+",
+        );
+        let meshes = load(&path, &Placement::default(), None).unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].num_faces(), 2);
+        assert_eq!(meshes[1].num_faces(), 1);
+
+        let ray = Ray::new(Vec3::new(0.75, 0.25, 1.), Vec3::new(0., 0., -1.), 0.);
+        let hit = meshes[0].hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.uv.0 - 0.75).abs() < 1e-5);
+        assert!((hit.uv.1 - 0.25).abs() < 1e-5);
+        match hit.material {
+            Material::Lambertian(_) => {}
+            other => panic!("expected a lambertian material, got {:?}", other),
+        }
+        let ray = Ray::new(Vec3::new(0.25, 0.75, 1.), Vec3::new(0., 0., -1.), 0.);
+        let hit = meshes[1].hit(&ray, 0.001, f32::MAX);
+        match hit.map(|hit| hit.material) {
+            Some(Material::Diffuse(_)) => {}
+            other => panic!("expected to hit a light, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_smoothing_groups() {
+        // two faces folded along the y axis
+        let path = write_model(
+            "smoothing",
+            "v 0 0 0
+v 0 1 0
+v 1 0 1
+v -1 0 1
+s 1
+f 1 3 2
+f 1 2 4
+",
+            "",
+        );
+        let transform = Placement {
+            translate: Vec3::new(0., 0., -5.),
+            ..Placement::default()
+        };
+        let meshes = load(&path, &transform, None).unwrap();
+        let ray = Ray::new(Vec3::new(0.01, 0.5, 0.), Vec3::new(0., 0., -1.), 0.);
+        let hit = meshes[0].hit(&ray, 0.001, f32::MAX).unwrap();
+        // near the fold the normal is close to the average of both faces
+        assert!((hit.normal - Vec3::new(0., 0., 1.)).length() < 0.02);
+    }
+
+    #[test]
+    fn test_models_are_only_shared_within_a_scene() {
+        let triangle = |size| format!("v 0 0 0\nv {} 0 0\nv 0 {} 0\nf 1 2 3\n", size, size);
+        let path = write_model("shared", &triangle(1), "");
+        let directory = path.parent().unwrap();
+        let load_scene = || {
+            load::relative_to(directory, || {
+                load::scene(|| {
+                    serde_yaml::from_str::<Vec<Model>>("[{path: model.obj}, {path: model.obj}]")
+                        .unwrap()
+                })
+            })
+        };
+
+        let (models, files) = load_scene();
+        assert!(Arc::ptr_eq(&models[0].objects, &models[1].objects));
+        assert_eq!(models[0].description.path, path.to_string_lossy());
+        assert_eq!(files, std::slice::from_ref(&path));
+
+        // The next scene sees the file as it is now
+        fs::write(&path, triangle(2)).unwrap();
+        let (reloaded, _) = load_scene();
+        assert!(!Arc::ptr_eq(&models[0].objects, &reloaded[0].objects));
+        let size = |model: &Model| model.bounding_box((0., 1.)).max().x();
+        assert!(size(&reloaded[0]) > size(&models[0]) + 0.5);
+    }
+}
@@ -0,0 +1,71 @@
+//! Saves films as images, in whichever format the file name asks for
+
+use crate::exr::{self, Precision};
+use crate::tonemap::ToneMapping;
+use crate::{pfm, renderer, Film};
+use std::error::Error;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Tone mapped to 8 bits per channel
+    Png,
+    /// Linear floats, with layers for the sample counts and variance
+    Exr(Precision),
+    /// Linear floats
+    Pfm,
+}
+
+impl Format {
+    /// The format for `path`'s extension. Anything that isn't EXR or
+    /// PFM is saved as a PNG.
+    pub fn from_path(path: &Path, precision: Precision) -> Format {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("exr") => Format::Exr(precision),
+            Some("pfm") => Format::Pfm,
+            _ => Format::Png,
+        }
+    }
+
+    /// Writes `film`, tone mapping it if the format needs 8 bit colors
+    pub fn write(
+        self,
+        writer: &mut impl Write,
+        film: &Film,
+        tone_mapping: &ToneMapping,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Format::Png => renderer::write_png(
+                writer,
+                (film.width(), film.height()),
+                &film.to_rgb8(tone_mapping),
+            )?,
+            Format::Exr(precision) => exr::write_film(writer, film, precision)?,
+            Format::Pfm => pfm::write_film(writer, film)?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes to a temporary file next to `path`, then moves it into place,
+/// so `path` is never left half written if rendering is stopped
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<fs::File>) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".partial");
+    let partial_path = path.with_file_name(file_name);
+    let mut output = BufWriter::new(fs::File::create(&partial_path)?);
+    write(&mut output)?;
+    output.flush()?;
+    drop(output);
+    fs::rename(partial_path, path)?;
+    Ok(())
+}
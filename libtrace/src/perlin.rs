@@ -1,72 +1,70 @@
+use crate::random::TraceRng;
 use crate::Vec3;
-use lazy_static::lazy_static;
-use rand::{thread_rng, Rng};
+use rand::{Rng, SeedableRng};
 use serde_derive::{Deserialize, Serialize};
-
-fn perlin_generate() -> Vec<Vec3> {
-    let mut rng = thread_rng();
-
-    let mut buf = Vec::with_capacity(256);
-    for _ in 0..256 {
-        let vec = Vec3::from(|| rng.gen_range(-1., 1.)).into_normalized();
-        buf.push(vec);
-    }
-    buf
-}
-
-fn perlin_generate_float() -> Vec<f32> {
-    let mut rng = thread_rng();
-
-    let mut buf: Vec<f32> = Vec::with_capacity(256);
-    for _ in 0..256 {
-        buf.push(rng.gen::<f32>());
-    }
-    buf
+use std::sync::Arc;
+
+/// The random gradients and permutations that noise is built from. They
+/// come from a seed so the pattern is the same every time it's
+/// rendered.
+#[derive(Debug)]
+struct Perlin {
+    ran_vec: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
 }
 
-fn perlin_generate_perm() -> Vec<usize> {
-    let mut p: Vec<_> = (0..256).collect();
-    thread_rng().shuffle(&mut p);
-    p
-}
-
-lazy_static! {
-    static ref PERM_X: Vec<usize> = perlin_generate_perm();
-    static ref PERM_Y: Vec<usize> = perlin_generate_perm();
-    static ref PERM_Z: Vec<usize> = perlin_generate_perm();
-    static ref RAN_VEC: Vec<Vec3> = perlin_generate();
-    static ref RAN_FLOAT: Vec<f32> = perlin_generate_float();
-}
-
-fn turbulence(mut p: Vec3, depth: usize) -> f32 {
-    let mut accum = 0.0;
-    let mut weight = 1.0;
-    for _ in 0..depth {
-        accum += weight * noise(p);
-        weight *= 0.5;
-        p *= 2.;
+impl Perlin {
+    fn new(seed: u64) -> Perlin {
+        let mut rng = TraceRng::seed_from_u64(seed);
+        let ran_vec = (0..256)
+            .map(|_| Vec3::from(|| rng.gen_range(-1., 1.)).into_normalized())
+            .collect();
+        let mut perm = || {
+            let mut p: Vec<_> = (0..256).collect();
+            rng.shuffle(&mut p);
+            p
+        };
+        Perlin {
+            perm_x: perm(),
+            perm_y: perm(),
+            perm_z: perm(),
+            ran_vec,
+        }
     }
-    accum.abs()
-}
 
-fn noise(p: Vec3) -> f32 {
-    fn add_and_usize(a: i32, b: i32) -> usize {
-        ((a + b) & 255) as usize
+    fn turbulence(&self, mut p: Vec3, depth: usize) -> f32 {
+        let mut accum = 0.0;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accum += weight * self.noise(p);
+            weight *= 0.5;
+            p *= 2.;
+        }
+        accum.abs()
     }
 
-    let (i, j, k) = p.apply(|v| v.floor()).to_tuple_and(|val| val as i32);
+    fn noise(&self, p: Vec3) -> f32 {
+        fn add_and_usize(a: i32, b: i32) -> usize {
+            ((a + b) & 255) as usize
+        }
 
-    let mut buf = [Vec3::from(0.0); 8];
-    for di in 0..=1i32 {
-        for dj in 0..=1i32 {
-            for dk in 0..=1i32 {
-                buf[(di * 4 + dj * 2 + dk) as usize] = RAN_VEC[PERM_X[add_and_usize(i, di)]
-                    ^ PERM_Y[add_and_usize(j, dj)]
-                    ^ PERM_Z[add_and_usize(k, dk)]]
+        let (i, j, k) = p.apply(|v| v.floor()).to_tuple_and(|val| val as i32);
+
+        let mut buf = [Vec3::from(0.0); 8];
+        for di in 0..=1i32 {
+            for dj in 0..=1i32 {
+                for dk in 0..=1i32 {
+                    buf[(di * 4 + dj * 2 + dk) as usize] = self.ran_vec[self.perm_x
+                        [add_and_usize(i, di)]
+                        ^ self.perm_y[add_and_usize(j, dj)]
+                        ^ self.perm_z[add_and_usize(k, dk)]]
+                }
             }
         }
+        perlin_interp(buf, p)
     }
-    perlin_interp(buf, p)
 }
 
 fn perlin_interp(c: [Vec3; 8], p: Vec3) -> f32 {
@@ -100,22 +98,56 @@ fn perlin_interp(c: [Vec3; 8], p: Vec3) -> f32 {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+struct NoiseDescription {
+    scale: f32,
+    /// Picks which pattern of noise to use
+    #[serde(default)]
+    seed: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "NoiseDescription", into = "NoiseDescription")]
 pub struct NoiseTexture {
     scale: f32,
+    seed: u64,
+    perlin: Arc<Perlin>,
+}
+
+impl From<NoiseDescription> for NoiseTexture {
+    fn from(description: NoiseDescription) -> NoiseTexture {
+        NoiseTexture::with_seed(description.scale, description.seed)
+    }
+}
+
+impl From<NoiseTexture> for NoiseDescription {
+    fn from(texture: NoiseTexture) -> NoiseDescription {
+        NoiseDescription {
+            scale: texture.scale,
+            seed: texture.seed,
+        }
+    }
 }
 
 impl Default for NoiseTexture {
     fn default() -> NoiseTexture {
-        NoiseTexture { scale: 1.0 }
+        NoiseTexture::new(1.0)
     }
 }
 
 impl NoiseTexture {
     pub fn new(scale: f32) -> NoiseTexture {
-        NoiseTexture { scale }
+        NoiseTexture::with_seed(scale, 0)
+    }
+
+    pub fn with_seed(scale: f32, seed: u64) -> NoiseTexture {
+        NoiseTexture {
+            scale,
+            seed,
+            perlin: Arc::new(Perlin::new(seed)),
+        }
     }
 
     pub(crate) fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
-        (0.5 * (1. + (self.scale * p.z() + 10. * turbulence(p, 4)).sin())).into()
+        (0.5 * (1. + (self.scale * p.z() + 10. * self.perlin.turbulence(p, 4)).sin())).into()
     }
 }
@@ -0,0 +1,24 @@
+//! Writes Portable Float Map images, which store linear RGB as 32 bit
+//! floats
+
+use crate::Film;
+use std::io::{self, Write};
+
+/// Writes the average color of every pixel in `film`
+pub fn write_film(writer: &mut impl Write, film: &Film) -> io::Result<()> {
+    // A negative scale means little endian
+    write!(writer, "PF\n{} {}\n-1.0\n", film.width(), film.height())?;
+    let pixels: Vec<_> = film.pixels().collect();
+    let width = film.width() as usize;
+    // Rows go from the bottom of the image to the top
+    for row in pixels.chunks(width).rev() {
+        let mut line = Vec::with_capacity(width * 12);
+        for pixel in row {
+            line.extend_from_slice(&pixel.x().to_le_bytes());
+            line.extend_from_slice(&pixel.y().to_le_bytes());
+            line.extend_from_slice(&pixel.z().to_le_bytes());
+        }
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
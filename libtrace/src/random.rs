@@ -0,0 +1,31 @@
+//! Seeded random numbers. Every sample of every pixel gets its own
+//! generator, seeded from the scene's seed and where the sample is, so
+//! a scene always renders the same way no matter how the work is split
+//! up between threads or machines.
+
+use rand::prng::XorShiftRng;
+use rand::SeedableRng;
+
+/// The random number generator used while tracing
+pub type TraceRng = XorShiftRng;
+
+/// The generator for sample number `sample` of the pixel at `(i, j)`
+pub fn sample_rng(seed: u64, (i, j): (u32, u32), sample: u32) -> TraceRng {
+    TraceRng::seed_from_u64(hash(seed, &[u64::from(i), u64::from(j), u64::from(sample)]))
+}
+
+/// Mixes `values` into `seed`, so that changing any of them gives an
+/// unrelated result
+pub(crate) fn hash(seed: u64, values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(mix(seed), |hash, &value| mix(hash ^ value))
+}
+
+/// The SplitMix64 finalizer, which spreads nearby values far apart
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -1,16 +1,18 @@
 use crate::aabb::Aabb;
+use crate::light::{self, Sampling, Shape};
+use crate::transform::Matrix4;
 use crate::{HitRecord, Material, Ray, Vec3};
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
+#[serde(tag = "plane")]
 pub enum Rect {
     XY(XYRect),
     XZ(XZRect),
     YZ(YZRect),
 }
 impl Rect {
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         match self {
             Rect::XY(rect) => rect.hit(ray, t_min, t_max),
             Rect::YZ(rect) => rect.hit(ray, t_min, t_max),
@@ -24,6 +26,13 @@ impl Rect {
             Rect::XZ(rect) => rect.bounding_box(time),
         }
     }
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        match self {
+            Rect::XY(rect) => rect.emitters(matrix, lights),
+            Rect::YZ(rect) => rect.emitters(matrix, lights),
+            Rect::XZ(rect) => rect.emitters(matrix, lights),
+        }
+    }
 }
 impl From<XYRect> for Rect {
     fn from(rect: XYRect) -> Rect {
@@ -71,7 +80,7 @@ impl XYRect {
         }
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.plane_offset - ray.origin().z()) / ray.direction().z();
         if t < t_min || t > t_max {
             return None;
@@ -87,12 +96,26 @@ impl XYRect {
                 (y - self.y0) / (self.y1 - self.y0),
             ),
             t,
-            material: self.material.clone(),
+            material: &self.material,
             pointing_at: ray.point_at(t),
             normal: (0., 0., 1.).into(),
+            geometric_normal: (0., 0., 1.).into(),
+            sampling: Sampling::Always,
         })
     }
-    pub fn bounding_box(&self, time: (f32, f32)) -> Aabb {
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            let shape = Shape::Parallelogram {
+                corner: Vec3::from((self.x0, self.y0, self.plane_offset)),
+                edges: (
+                    (self.x1 - self.x0, 0., 0.).into(),
+                    (0., self.y1 - self.y0, 0.).into(),
+                ),
+            };
+            light::add_emitter(lights, shape, matrix);
+        }
+    }
+    pub fn bounding_box(&self, _time: (f32, f32)) -> Aabb {
         Aabb::new(
             (self.x0, self.y0, self.plane_offset - 0.0001).into(),
             (self.x1, self.y1, self.plane_offset + 0.0001).into(),
@@ -129,7 +152,7 @@ impl XZRect {
         }
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.plane_offset - ray.origin().y()) / ray.direction().y();
         if t < t_min || t > t_max {
             return None;
@@ -145,12 +168,26 @@ impl XZRect {
                 (z - self.z0) / (self.z1 - self.z0),
             ),
             t,
-            material: self.material.clone(),
+            material: &self.material,
             pointing_at: ray.point_at(t),
             normal: (0., 1., 0.).into(),
+            geometric_normal: (0., 1., 0.).into(),
+            sampling: Sampling::Always,
         })
     }
-    pub fn bounding_box(&self, time: (f32, f32)) -> Aabb {
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            let shape = Shape::Parallelogram {
+                corner: Vec3::from((self.x0, self.plane_offset, self.z0)),
+                edges: (
+                    (self.x1 - self.x0, 0., 0.).into(),
+                    (0., 0., self.z1 - self.z0).into(),
+                ),
+            };
+            light::add_emitter(lights, shape, matrix);
+        }
+    }
+    pub fn bounding_box(&self, _time: (f32, f32)) -> Aabb {
         Aabb::new(
             (self.x0, self.plane_offset - 0.0001, self.z0).into(),
             (self.x1, self.plane_offset + 0.0001, self.z1).into(),
@@ -187,14 +224,14 @@ impl YZRect {
         }
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.plane_offset - ray.origin().x()) / ray.direction().x();
         if t < t_min || t > t_max {
             return None;
         }
         let y = ray.origin().y() + t * ray.direction().y();
         let z = ray.origin().z() + t * ray.direction().z();
-        if y < self.y0 || y > self.y1 || z < self.z0 || y > self.z1 {
+        if y < self.y0 || y > self.y1 || z < self.z0 || z > self.z1 {
             return None;
         }
         Some(HitRecord {
@@ -203,12 +240,26 @@ impl YZRect {
                 (z - self.z0) / (self.z1 - self.z0),
             ),
             t,
-            material: self.material.clone(),
+            material: &self.material,
             pointing_at: ray.point_at(t),
             normal: (1., 0., 0.).into(),
+            geometric_normal: (1., 0., 0.).into(),
+            sampling: Sampling::Always,
         })
     }
-    pub fn bounding_box(&self, time: (f32, f32)) -> Aabb {
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            let shape = Shape::Parallelogram {
+                corner: Vec3::from((self.plane_offset, self.y0, self.z0)),
+                edges: (
+                    (0., self.y1 - self.y0, 0.).into(),
+                    (0., 0., self.z1 - self.z0).into(),
+                ),
+            };
+            light::add_emitter(lights, shape, matrix);
+        }
+    }
+    pub fn bounding_box(&self, _time: (f32, f32)) -> Aabb {
         Aabb::new(
             (self.plane_offset - 0.0001, self.y0, self.z0).into(),
             (self.plane_offset + 0.0001, self.y1, self.z1).into(),
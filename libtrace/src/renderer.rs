@@ -1,14 +1,30 @@
-use crate::{scene::Scene, Camera, Hitable, Vec3};
-use rand::prelude::*;
+use crate::sampler::Sampler;
+use crate::scene::{Image, Scene};
+use crate::tiles::Tile;
+use crate::{Camera, Film, Hitable, Lights, PixelSamples, Vec3};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 /// A trait to help you define your own renderer.
 /// Most of the guts of rendering are already provided for you, but
 /// you are free to customize those methods as well.
-pub trait Renderer {
+pub trait Renderer: Sync {
     fn scene(&self) -> &Scene;
-    fn objects(&self) -> &Hitable;
+
+    /// The world that rays are traced against. By default this is
+    /// whatever the scene describes.
+    fn objects(&self) -> &Hitable {
+        &self.scene().objects
+    }
+
+    /// The emissive surfaces in `objects`, which are sampled directly
+    /// at every diffuse bounce. Build these once with `Scene::lights`.
+    fn lights(&self) -> &Lights;
 
     #[inline]
     fn camera(&self, scene: &Scene) -> Camera {
@@ -31,89 +47,351 @@ pub trait Renderer {
         )
     }
 
-    fn write_image(
-        &self,
-        buffer: &mut impl Write,
-        pixels: &[(u8, u8, u8)],
-    ) -> Result<(), Box<dyn Error>> {
-        use png::HasParameters;
+    fn write_image(&self, buffer: &mut impl Write, film: &Film) -> Result<(), Box<dyn Error>> {
+        let scene = self.scene();
+        write_png(
+            buffer,
+            (film.width(), film.height()),
+            &film.to_rgb8(&scene.image.tone_mapping),
+        )
+    }
+
+    /// The tiles to render, in the order to render them
+    fn get_tiles_to_render(&self, image: &Image) -> Vec<Tile> {
+        image.tiles.split(image)
+    }
+
+    fn render(&self) -> Film {
+        self.render_pass(&self.scene().image, None)
+    }
 
+    /// Renders the scene, but with the samples that `image` asks for,
+    /// like one of the passes from `Image::passes`. `previous` is what
+    /// the passes before this one rendered, if there were any. The
+    /// tiles are rendered in parallel, one per thread.
+    fn render_pass(&self, image: &Image, previous: Option<&Film>) -> Film {
         let scene = self.scene();
+        let camera = self.camera(scene);
+        let tiles = self.get_tiles_to_render(image);
 
-        let writer = BufWriter::new(buffer);
-        let mut encoder = png::Encoder::new(writer, scene.image.width(), scene.image.height());
-        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
-        let mut image_data = Vec::with_capacity(pixels.len() * 4);
-
-        for pixel in pixels {
-            image_data.push(pixel.0);
-            image_data.push(pixel.1);
-            image_data.push(pixel.2);
-            image_data.push(255);
+        // Tiles are handed out in order, and their samples are added to
+        // the film in that order too, so the film comes out exactly the
+        // same however the threads are scheduled
+        let next_tile = AtomicUsize::new(0);
+        let finished = Mutex::new(FinishedTiles {
+            film: image.film(),
+            next: 0,
+            waiting: BTreeMap::new(),
+        });
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                let tile = match tiles.get(index) {
+                    Some(&tile) => tile,
+                    None => break,
+                };
+                let samples = tile
+                    .pixels()
+                    .filter(|&location| {
+                        let skip = self.skip_pixel(image, previous, location);
+                        if skip {
+                            self.on_pixel_skipped(location);
+                        }
+                        !skip
+                    })
+                    .map(|location| {
+                        let location = image.camera_location(location);
+                        self.render_pixel(&camera, location, image)
+                    })
+                    .collect();
+                finished.lock().unwrap().add(index, samples);
+            });
+        finished.into_inner().unwrap().film
+    }
+
+    /// Whether to leave out the pixel at `location`, counting from the
+    /// top left, when rendering `image`. When the image is adaptive,
+    /// pixels that earlier passes already took enough samples of are
+    /// left out.
+    fn skip_pixel(&self, image: &Image, previous: Option<&Film>, location: (u32, u32)) -> bool {
+        match (&image.adaptive, previous) {
+            (Some(adaptive), Some(film)) => adaptive.is_pixel_converged(film, location),
+            _ => false,
         }
+    }
 
-        writer.write_image_data(&image_data)?;
-        Ok(())
+    /// Renders the image in passes of `samples_per_pass`, until either
+    /// every sample has been taken or there isn't time for another pass
+    /// before the image's time limit. Returns the film along with how
+    /// many samples of each pixel were taken.
+    fn render_passes(&self, samples_per_pass: u32) -> (Film, u32) {
+        let image = &self.scene().image;
+        let mut budget = TimeBudget::new(image.time_limit());
+        let mut film = image.film();
+        let mut samples_taken = 0;
+        for pass in image.passes(samples_per_pass) {
+            if !budget.has_time_for_pass() {
+                break;
+            }
+            let rendered = budget.time_pass(|| self.render_pass(&pass, Some(&film)));
+            film.merge(&rendered);
+            samples_taken += pass.samples;
+        }
+        (film, samples_taken)
     }
 
-    #[inline]
-    fn get_pixels_to_render(&self, scene: &Scene) -> Vec<(u32, u32)> {
-        let (top, height) = match &scene.image.slice {
-            Some(slice) => (scene.image.height - slice.top, slice.height),
-            None => (0, scene.image.height),
-        };
+    /// Traces the samples of a pixel. That's `samples` of them, unless
+    /// the image is adaptive, when sampling stops as soon as the pixel
+    /// has converged.
+    fn render_pixel(&self, camera: &Camera, location: (u32, u32), image: &Image) -> PixelSamples {
+        let scene = self.scene();
+        let width = image.width as f32;
+        let height = image.height as f32;
+        let num_samples = image.samples;
+
+        let i = location.0 as f32;
+        let j = location.1 as f32;
 
-        let mut pixels = Vec::with_capacity(scene.image.num_pixels() as usize);
+        let mut sampler = Sampler::new(scene.sampler, scene.seed, location, image.total_samples());
+        let mut samples =
+            PixelSamples::new(scene.image.raster_location(location), scene.image.filter);
+        while samples.count < num_samples {
+            // Every sample is numbered, so it comes out the same however
+            // the pixels are shared out
+            sampler.start_sample(image.first_sample + samples.count);
+            // U and V are the actual coordinates on the
+            // image plane we are targeting.
+            // the sampler adds a tiny bit of "wobble"
+            // to our sample, which is good for sampling
+            let (du, dv) = sampler.get_2d();
+            let u = (i + du) / width;
+            let v = (j + dv) / height;
+            let r = camera.get_ray(u, v, &mut sampler);
+            let radiance = crate::color(
+                &r,
+                self.objects(),
+                self.lights(),
+                &scene.background,
+                &mut sampler,
+            );
+            // The film counts rows down from the top, while V counts up
+            samples.add((du, 1. - dv), radiance);
+            if let Some(adaptive) = &image.adaptive {
+                if adaptive.is_converged(&samples) {
+                    break;
+                }
+            }
+        }
+        self.on_pixel_rendered(location, samples.mean());
+        samples
+    }
+    fn on_pixel_rendered(&self, _location: (u32, u32), _color: Vec3) {}
+    /// Called instead of `on_pixel_rendered` for pixels that
+    /// `skip_pixel` leaves out
+    fn on_pixel_skipped(&self, _location: (u32, u32)) {}
+}
 
-        for j in 0..height {
-            let j = height - 1 - j;
-            let j = j + top;
+/// The tiles of a pass that have been rendered, waiting for the ones
+/// before them to be added to the film
+struct FinishedTiles {
+    film: Film,
+    /// The next tile to add to the film
+    next: usize,
+    waiting: BTreeMap<usize, Vec<PixelSamples>>,
+}
 
-            for i in 0..scene.image.width() {
-                pixels.push((i, j));
+impl FinishedTiles {
+    fn add(&mut self, index: usize, samples: Vec<PixelSamples>) {
+        self.waiting.insert(index, samples);
+        while let Some(samples) = self.waiting.remove(&self.next) {
+            for pixel in &samples {
+                self.film.add_samples(pixel);
             }
+            self.next += 1;
         }
-        pixels
     }
+}
 
-    fn render(&self) -> Vec<(u8, u8, u8)> {
-        let scene = self.scene();
-        let camera = self.camera(&scene);
+/// Keeps track of how long rendering has taken, to stop before a time
+/// limit
+#[derive(Debug, Clone)]
+pub struct TimeBudget {
+    started: Instant,
+    limit: Option<Duration>,
+    longest_pass: Duration,
+}
 
-        self.get_pixels_to_render(&scene)
-            .into_iter()
-            .map(|(i, j)| self.render_pixel(&camera, (i, j), &scene))
-            .collect()
+impl TimeBudget {
+    /// Starts timing now. There's no limit if `limit` is `None`.
+    pub fn new(limit: Option<Duration>) -> TimeBudget {
+        TimeBudget {
+            started: Instant::now(),
+            limit,
+            longest_pass: Duration::default(),
+        }
     }
 
-    fn render_pixel(&self, camera: &Camera, location: (u32, u32), scene: &Scene) -> (u8, u8, u8) {
-        let width = scene.image.width as f32;
-        let height = scene.image.height as f32;
-        let num_samples = scene.image.samples;
+    /// Whether another pass would finish before the limit, assuming it
+    /// takes as long as the longest one so far
+    pub fn has_time_for_pass(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.elapsed() + self.longest_pass <= limit,
+            None => true,
+        }
+    }
 
-        let i = location.0 as f32;
-        let j = location.1 as f32;
+    /// Runs a pass, timing how long it takes
+    pub fn time_pass<T>(&mut self, pass: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = pass();
+        self.longest_pass = self.longest_pass.max(started.elapsed());
+        result
+    }
 
-        let mut rng = rand::thread_rng();
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
 
-        let mut samples = Vec::new();
-        samples.reserve(num_samples as usize);
-        for _ in 0..num_samples {
-            // U and V are the actual coordinates on the
-            // image plane we are targeting.
-            // the rand adds a tiny bit of "wobble"
-            // to our sample, which 2is good for sampling
-            let u = (i + rng.gen::<f32>()) / width;
-            let v = (j + rng.gen::<f32>()) / height;
-            let r = camera.get_ray(u, v);
-            samples.push(crate::color(&r, self.objects(), 0));
+/// Writes 8 bit pixels, row by row from the top, as an RGBA PNG
+pub fn write_png(
+    buffer: &mut impl Write,
+    (width, height): (u32, u32),
+    pixels: &[(u8, u8, u8)],
+) -> Result<(), Box<dyn Error>> {
+    use png::HasParameters;
+
+    let writer = BufWriter::new(buffer);
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let mut image_data = Vec::with_capacity(pixels.len() * 4);
+
+    for pixel in pixels {
+        image_data.push(pixel.0);
+        image_data.push(pixel.1);
+        image_data.push(pixel.2);
+        image_data.push(255);
+    }
+
+    writer.write_image_data(&image_data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+
+    struct TestRenderer {
+        scene: Scene,
+        lights: Lights,
+    }
+
+    impl Renderer for TestRenderer {
+        fn scene(&self) -> &Scene {
+            &self.scene
         }
-        let col: Vec3 = samples.into_iter().sum();
-        let color = col / num_samples as f32;
-        let color = crate::ppm::to_color(&color);
-        self.on_pixel_rendered(location, color);
-        color
+
+        fn lights(&self) -> &Lights {
+            &self.lights
+        }
+    }
+
+    fn scene(seed: u64) -> Scene {
+        let mut scene: Scene = serde_yaml::from_str(
+            "
+image: {width: 4, height: 3, samples: 2, slice: null}
+camera: {look_from: [13, 2, 3], look_at: [0, 0, 0], aperture: 0.5, fov: 20}
+background: {type: Gradient, bottom: [1, 1, 1], top: [0.5, 0.7, 1]}
+objects:
+  type: StaticSphere
+  radius: 2
+  center: [0, 2, 0]
+  material: {type: Lambertian, albedo: {type: Noise, scale: 4}}
+",
+        )
+        .unwrap();
+        scene.seed = seed;
+        scene
+    }
+
+    fn renderer(scene: Scene) -> TestRenderer {
+        TestRenderer {
+            lights: scene.lights(),
+            scene,
+        }
+    }
+
+    fn render(seed: u64) -> Vec<Vec3> {
+        renderer(scene(seed)).render().pixels().collect()
+    }
+
+    #[test]
+    fn test_seeded_renders_are_reproducible() {
+        assert_eq!(render(1), render(1));
+        assert_ne!(render(1), render(2));
+    }
+
+    #[test]
+    fn test_renders_dont_depend_on_how_many_threads_there_are() {
+        let mut scene = scene(1);
+        scene.image.tiles.size = 1;
+        let renderer = renderer(scene);
+        let render = |threads: usize| -> Vec<Vec3> {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| renderer.render().pixels().collect())
+        };
+        assert_eq!(render(1), render(4));
+    }
+
+    #[test]
+    fn test_passes_add_up_to_one_render() {
+        for &sampler in &[
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let mut scene = scene(1);
+            scene.sampler = sampler;
+            scene.image.samples = 9;
+            let renderer = renderer(scene);
+            let (passes, samples_taken) = renderer.render_passes(4);
+            assert_eq!(samples_taken, 9);
+            for (one, merged) in renderer.render().pixels().zip(passes.pixels()) {
+                assert!((one - merged).length() < 1e-5, "{:?}", sampler);
+            }
+        }
+    }
+
+    /// Pixels that earlier passes took enough samples of aren't sampled
+    /// again, even when one pass alone has too few to tell
+    #[test]
+    fn test_adaptive_passes_stop_early() {
+        let mut scene = scene(1);
+        scene.image.samples = 64;
+        scene.image.adaptive = serde_yaml::from_str("{min_samples: 12, threshold: 1000}").unwrap();
+        let (film, samples_taken) = renderer(scene).render_passes(8);
+        assert_eq!(samples_taken, 64);
+        for y in 0..3 {
+            for x in 0..4 {
+                assert_eq!(film.samples((x, y)), 16);
+            }
+        }
+    }
+
+    #[test]
+    fn test_time_budget_stops_before_a_pass_would_overrun() {
+        let mut budget = TimeBudget::new(Some(Duration::from_millis(150)));
+        assert!(budget.has_time_for_pass());
+        budget.time_pass(|| std::thread::sleep(Duration::from_millis(100)));
+        // Another pass as long as that one wouldn't finish in time
+        assert!(!budget.has_time_for_pass());
+        assert!(TimeBudget::new(None).has_time_for_pass());
     }
-    fn on_pixel_rendered(&self, _location: (u32, u32), _color: (u8, u8, u8)) {}
 }
@@ -0,0 +1,313 @@
+//! Where the numbers that drive each sample come from. Every sample
+//! uses them in the same order, one dimension at a time: first where it
+//! lands in the pixel, then the lens and the shutter time, then a fixed
+//! block of dimensions for every bounce. Low discrepancy samplers spread
+//! each dimension evenly over a pixel's samples, which converges much
+//! faster than picking them independently.
+
+use crate::random::{hash, sample_rng, TraceRng};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The dimensions used for the pixel position, lens and time
+const CAMERA_DIMENSIONS: u32 = 5;
+/// The dimensions set aside for every bounce of a path
+const BOUNCE_DIMENSIONS: u32 = 8;
+
+/// How the numbers for each dimension are picked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SamplerKind {
+    /// Every number is picked at random
+    #[default]
+    Independent,
+    /// The samples of a pixel are jittered within separate strata
+    Stratified,
+    /// The Halton sequence, shifted randomly for each pixel
+    Halton,
+    /// The Sobol sequence, with Owen scrambling for each pixel
+    Sobol,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SamplerKind, String> {
+        match s.to_lowercase().as_str() {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler {:?}", s)),
+        }
+    }
+}
+
+/// Hands out the numbers for the samples of one pixel
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,
+    pixel: (u32, u32),
+    /// How many samples of the pixel the whole render takes, even if
+    /// they're taken a few at a time
+    samples: u32,
+    index: u32,
+    dimension: u32,
+    rng: TraceRng,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, pixel: (u32, u32), samples: u32) -> Sampler {
+        Sampler {
+            kind,
+            seed,
+            pixel,
+            samples: samples.max(1),
+            index: 0,
+            dimension: 0,
+            rng: sample_rng(seed, pixel, 0),
+        }
+    }
+
+    /// Starts sample number `index` of the pixel from its first
+    /// dimension
+    pub fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+        self.rng = sample_rng(self.seed, self.pixel, index);
+    }
+
+    /// Moves on to the dimensions for bounce number `depth`, so every
+    /// bounce uses the same dimensions however many the bounces before
+    /// it used
+    pub fn start_bounce(&mut self, depth: usize) {
+        self.dimension = CAMERA_DIMENSIONS + depth as u32 * BOUNCE_DIMENSIONS;
+    }
+
+    /// A random number generator for the current sample, for anything
+    /// that needs an unknown amount of random numbers
+    pub fn rng(&mut self) -> &mut TraceRng {
+        &mut self.rng
+    }
+
+    /// The next number in [0, 1)
+    pub fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let value = match self.kind {
+            SamplerKind::Independent => self.rng.gen(),
+            SamplerKind::Stratified => self.stratified_1d(dimension),
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => self.sobol_2d(dimension).0,
+        };
+        value.min(ONE_MINUS_EPSILON)
+    }
+
+    /// The next pair of numbers in [0, 1)
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let (u, v) = match self.kind {
+            SamplerKind::Independent => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => self.stratified_2d(dimension),
+            SamplerKind::Halton => (self.halton(dimension), self.halton(dimension + 1)),
+            SamplerKind::Sobol => self.sobol_2d(dimension),
+        };
+        (u.min(ONE_MINUS_EPSILON), v.min(ONE_MINUS_EPSILON))
+    }
+
+    /// A hash of the pixel and dimension, to decorrelate pixels from
+    /// each other and dimensions from each other
+    fn hash(&self, dimension: u32, salt: u32) -> u32 {
+        let (i, j) = self.pixel;
+        let values = [
+            u64::from(i),
+            u64::from(j),
+            u64::from(dimension),
+            u64::from(salt),
+        ];
+        (hash(self.seed, &values) >> 32) as u32
+    }
+
+    /// Which of the pixel's `samples` strata this sample falls in. Each
+    /// run of `samples` samples covers every stratum once, in an order
+    /// that's shuffled for every dimension.
+    fn stratum(&self, dimension: u32, strata: u32) -> u32 {
+        let pass = self.index / strata;
+        permute(self.index % strata, strata, self.hash(dimension, pass))
+    }
+
+    fn stratified_1d(&mut self, dimension: u32) -> f32 {
+        let stratum = self.stratum(dimension, self.samples);
+        (stratum as f32 + self.rng.gen::<f32>()) / self.samples as f32
+    }
+
+    fn stratified_2d(&mut self, dimension: u32) -> (f32, f32) {
+        // As close to a square grid as the sample count allows. When
+        // there are more cells than samples, they're still picked
+        // evenly since the order is shuffled.
+        let columns = (self.samples as f32).sqrt().ceil() as u32;
+        let rows = self.samples.div_ceil(columns);
+        let stratum = self.stratum(dimension, columns * rows);
+        let (x, y) = (stratum % columns, stratum / columns);
+        (
+            (x as f32 + self.rng.gen::<f32>()) / columns as f32,
+            (y as f32 + self.rng.gen::<f32>()) / rows as f32,
+        )
+    }
+
+    fn halton(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let shift = self.hash(dimension, 0) as f32 / 4_294_967_296.;
+                let value = radical_inverse(base, self.index) + shift;
+                value - value.floor()
+            }
+            // There aren't enough primes for really long paths, but by
+            // then the dimensions hardly matter
+            None => self.rng.gen(),
+        }
+    }
+
+    /// A pair of dimensions from the first two dimensions of the Sobol
+    /// sequence. Every pair shuffles the order of the samples
+    /// differently, so the pairs aren't correlated with each other.
+    fn sobol_2d(&mut self, dimension: u32) -> (f32, f32) {
+        let index = owen_scramble(self.index, self.hash(dimension, 0));
+        let u = owen_scramble(index.reverse_bits(), self.hash(dimension, 1));
+        let v = owen_scramble(sobol_second_dimension(index), self.hash(dimension, 2));
+        (to_unit_float(u), to_unit_float(v))
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1. - f32::EPSILON / 2.;
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Mirrors the digits of `index` in `base` around the decimal point
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    let inverse_base = 1. / f64::from(base);
+    let mut scale = inverse_base;
+    let mut value = 0f64;
+    while index > 0 {
+        value += f64::from(index % base) * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    value as f32
+}
+
+/// The second dimension of the Sobol sequence, whose generator matrix
+/// is Pascal's triangle mod 2. The first dimension is just the index
+/// with its bits reversed.
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+/// Scrambles the bits of `value` so that each bit only depends on the
+/// bits above it, which keeps the sequence stratified. From Burley,
+/// "Practical Hash-based Owen Scrambling".
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+fn to_unit_float(value: u32) -> f32 {
+    (value >> 8) as f32 / (1 << 24) as f32
+}
+
+/// Shuffles `index`, which must be less than `length`, to another
+/// number less than `length`. Every seed gives a different
+/// permutation. From Kensler, "Correlated Multi-Jittered Sampling".
+fn permute(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= mask;
+        index ^= index >> 5;
+        if index < length {
+            break;
+        }
+    }
+    (index + seed) % length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first dimension should be spread evenly by every kind of
+    /// sampler, and later pairs of dimensions should be too, apart from
+    /// Halton whose larger bases need more samples
+    #[test]
+    fn test_samplers_cover_every_stratum() {
+        for &kind in &[
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let mut sampler = Sampler::new(kind, 3, (5, 7), 16);
+            let mut quarters = [0; 4];
+            let mut quadrants = [0; 4];
+            for index in 0..16 {
+                sampler.start_sample(index);
+                quarters[(sampler.get_1d() * 4.) as usize] += 1;
+                sampler.start_bounce(1);
+                let (u, v) = sampler.get_2d();
+                assert!((0. ..1.).contains(&u) && (0. ..1.).contains(&v));
+                quadrants[(u * 2.) as usize * 2 + (v * 2.) as usize] += 1;
+            }
+            assert_eq!(quarters, [4, 4, 4, 4], "{:?}", kind);
+            if kind != SamplerKind::Halton {
+                assert_eq!(quadrants, [4, 4, 4, 4], "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_permute_is_a_permutation() {
+        let mut seen: Vec<_> = (0..10).map(|index| permute(index, 10, 1234)).collect();
+        seen.sort();
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+    }
+}
@@ -1,18 +1,117 @@
-use crate::{Hitable, Vec3};
+use crate::filter::Filter;
+use crate::sampler::SamplerKind;
+use crate::tiles::Tiles;
+use crate::tonemap::ToneMapping;
+use crate::transform::Instances;
+use crate::{load, Background, BvhOptions, Film, Hitable, Lights, PixelSamples, Vec3};
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+/// Everything the scene refers to is loaded along with it. The
+/// derived implementations are wrapped below so that happens apart from
+/// any other scene.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(remote = "Self")]
 pub struct Scene {
     pub image: Image,
     pub camera: Camera,
+    /// What rays that escape the scene without hitting anything see
+    #[serde(default)]
+    pub background: Background,
+    /// Objects that `objects` can place by name any number of times.
+    /// They have to come before `objects` in scene files.
+    #[serde(default)]
+    pub instances: Instances,
+    /// Everything that can be hit in the scene
+    pub objects: Hitable,
+    /// How to build the hierarchy that the objects are placed into
+    #[serde(default)]
+    pub bvh: BvhOptions,
+    /// Where the random numbers used to render start from. Rendering
+    /// the same scene with the same seed always gives the same image.
+    #[serde(default)]
+    pub seed: u64,
+    /// How the random numbers for each sample are picked
+    #[serde(default)]
+    pub sampler: SamplerKind,
+    /// Every file that was read to load the scene, apart from the scene
+    /// file itself
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+impl<'de> serde::Deserialize<'de> for Scene {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Scene, D::Error> {
+        let (scene, files) = load::scene(|| Scene::deserialize(deserializer));
+        Ok(Scene { files, ..scene? })
+    }
+}
+
+impl serde::Serialize for Scene {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Scene::serialize(self, serializer)
+    }
+}
+
+impl Scene {
+    /// Loads the scene file at `path`. Files that the scene refers to
+    /// are found relative to it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Scene, Box<dyn Error>> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(load::relative_to(directory, || {
+            serde_yaml::from_reader(reader)
+        })?)
+    }
+
+    /// Finds all of the lights in the scene, including the background
+    /// if it's an environment map
+    pub fn lights(&self) -> Lights {
+        Lights::new(&self.objects, &self.background)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Image {
     pub height: u32,
     pub width: u32,
+    /// How many samples to take of each pixel, or the most to take
+    /// when sampling adaptively
     pub samples: u32,
     pub slice: Option<ImageSlice>,
+    /// Stops sampling pixels once they've stopped getting less noisy
+    #[serde(default)]
+    pub adaptive: Option<Adaptive>,
+    /// The number of the first sample to take of each pixel. Renders
+    /// of the same scene that start at different samples can be merged
+    /// without repeating any of them.
+    #[serde(default)]
+    pub first_sample: u32,
+    /// How many samples of each pixel the whole render takes, when this
+    /// image only takes some of them, like one of its passes. The
+    /// samples are stratified over all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_samples: Option<u32>,
+    /// How the rendered light is turned into 8 bit colors
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
+    /// How each sample is spread over the pixels around it
+    #[serde(default)]
+    pub filter: Filter,
+    /// How many seconds rendering in passes can take. No more passes
+    /// are started once the next one wouldn't finish in time, even if
+    /// that means taking fewer samples.
+    #[serde(default)]
+    pub time_limit: Option<f32>,
+    /// How the pixels are grouped together and ordered for rendering
+    #[serde(default)]
+    pub tiles: Tiles,
 }
 
 impl Image {
@@ -20,7 +119,10 @@ impl Image {
         self.width() * self.height()
     }
     pub fn width(&self) -> u32 {
-        self.width
+        match &self.slice {
+            Some(slice) => slice.width.unwrap_or(self.width - slice.left),
+            None => self.width,
+        }
     }
     #[inline]
     pub fn height(&self) -> u32 {
@@ -29,11 +131,131 @@ impl Image {
             .map(|slice| slice.height)
             .unwrap_or(self.height)
     }
+    /// The first row being rendered, counting down from the top of the
+    /// whole image
+    #[inline]
+    pub fn top(&self) -> u32 {
+        self.slice.as_ref().map(|slice| slice.top).unwrap_or(0)
+    }
+    /// The first column being rendered, counting from the left of the
+    /// whole image
+    #[inline]
+    pub fn left(&self) -> u32 {
+        self.slice.as_ref().map(|slice| slice.left).unwrap_or(0)
+    }
+    /// Where the pixel at `(i, j)` in camera coordinates, with `j`
+    /// counting up from the bottom of the whole image, is in the image,
+    /// counting down from the top
+    #[inline]
+    pub fn raster_location(&self, (i, j): (u32, u32)) -> (u32, u32) {
+        (i, self.height - 1 - j)
+    }
+    /// The opposite of `raster_location`
+    #[inline]
+    pub fn camera_location(&self, (x, y): (u32, u32)) -> (u32, u32) {
+        (x, self.height - 1 - y)
+    }
+    /// An empty film for the pixels being rendered. Samples near the
+    /// edge of a slice are spread into the pixels around it by the
+    /// filter, so those are included too, for when the slices are
+    /// stitched back together.
+    pub fn film(&self) -> Film {
+        let margin = self.filter.pixel_radius();
+        let left = self.left().saturating_sub(margin);
+        let right = (self.left() + self.width() + margin).min(self.width);
+        let top = self.top().saturating_sub(margin);
+        let bottom = (self.top() + self.height() + margin).min(self.height);
+        Film::region((left, top), (right - left, bottom - top))
+    }
+    /// An empty film for just the pixels being rendered, without the
+    /// margin around them
+    pub fn visible_film(&self) -> Film {
+        Film::region((self.left(), self.top()), (self.width(), self.height()))
+    }
+    pub fn total_samples(&self) -> u32 {
+        self.total_samples.unwrap_or(self.samples)
+    }
+    pub fn time_limit(&self) -> Option<Duration> {
+        self.time_limit
+            .map(|seconds| Duration::from_secs_f32(seconds.max(0.)))
+    }
+    /// Splits the samples into passes of up to `samples_per_pass`
+    /// each. Every pass is the same image, but only takes its own share
+    /// of the samples, so rendering all of them and merging the films
+    /// gives the same image as rendering this one.
+    pub fn passes(&self, samples_per_pass: u32) -> impl Iterator<Item = Image> + '_ {
+        let samples_per_pass = samples_per_pass.max(1);
+        (0..self.samples.div_ceil(samples_per_pass)).map(move |pass| {
+            let taken = pass * samples_per_pass;
+            Image {
+                samples: samples_per_pass.min(self.samples - taken),
+                first_sample: self.first_sample + taken,
+                total_samples: Some(self.total_samples()),
+                ..self.clone()
+            }
+        })
+    }
+}
+
+/// Settings for sampling each pixel until its noise drops below a
+/// threshold, rather than always taking the same number of samples
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Adaptive {
+    /// Every pixel takes at least this many samples, so that noise
+    /// from rare paths has a chance to show up
+    pub min_samples: u32,
+    /// How large the standard error of a pixel's luminance can be,
+    /// relative to its brightness, for it to be finished
+    #[serde(default = "Adaptive::default_threshold")]
+    pub threshold: f32,
+}
+
+impl Adaptive {
+    fn default_threshold() -> f32 {
+        0.02
+    }
+
+    /// Whether `samples` are enough to stop sampling the pixel
+    pub fn is_converged(&self, samples: &PixelSamples) -> bool {
+        self.converged(
+            samples.count,
+            samples.mean().luminance(),
+            samples.variance(),
+        )
+    }
+
+    /// Whether the samples `film` already has for the pixel at
+    /// `location` are enough to stop sampling it
+    pub fn is_pixel_converged(&self, film: &Film, location: (u32, u32)) -> bool {
+        self.converged(
+            film.samples(location),
+            film.luminance(location),
+            film.variance(location),
+        )
+    }
+
+    fn converged(&self, count: u32, luminance: f32, variance: f32) -> bool {
+        if count < self.min_samples.max(2) {
+            return false;
+        }
+        // Very dark pixels count as being a little brighter, otherwise
+        // they'd need endless samples to get the same relative error
+        let brightness = luminance.max(0.01);
+        variance.sqrt() <= self.threshold * brightness
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageSlice {
+    /// The first column to render. Slices start at the left edge of
+    /// the image unless they say otherwise.
+    #[serde(default)]
+    pub left: u32,
     pub top: u32,
+    /// How many columns to render, or all of them from `left` if this
+    /// isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
     pub height: u32,
 }
 
@@ -49,6 +271,65 @@ pub struct Camera {
 pub struct Rendered {
     /// The image that was being rendered
     pub image: Image,
-    /// The pixel data
-    pub pixels: Vec<(u8, u8, u8)>,
+    /// The samples taken for the pixels being rendered
+    pub film: Film,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_tags_round_trip() {
+        let yaml = r#"
+image:
+  height: 10
+  width: 20
+  samples: 1
+  slice: ~
+camera:
+  look_from: [0.0, 0.0, -1.0]
+  look_at: [0.0, 0.0, 0.0]
+  aperture: 0.0
+  fov: 40.0
+objects:
+  type: NormalFlipper
+  inner:
+    type: Rect
+    plane: XY
+    x0: 0.0
+    x1: 1.0
+    y0: 0.0
+    y1: 1.0
+    plane_offset: 0.0
+    material:
+      type: Diffuse
+      emit:
+        type: Color
+        color: [1.0, 1.0, 1.0]
+"#;
+        let scene: Scene = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            scene.background.value(Vec3::new(0., 1., 0.)),
+            Vec3::new(0., 0., 0.)
+        );
+        let reparsed: Scene =
+            serde_yaml::from_str(&serde_yaml::to_string(&scene).unwrap()).unwrap();
+        match reparsed.objects {
+            Hitable::NormalFlipper(_) => {}
+            other => panic!("expected a NormalFlipper, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_passes_take_every_sample_once() {
+        let image: Image =
+            serde_yaml::from_str("{height: 1, width: 1, samples: 10, slice: ~, first_sample: 20}")
+                .unwrap();
+        let passes: Vec<_> = image
+            .passes(4)
+            .map(|pass| (pass.first_sample, pass.samples, pass.total_samples()))
+            .collect();
+        assert_eq!(passes, [(20, 4, 10), (24, 4, 10), (28, 2, 10)]);
+    }
 }
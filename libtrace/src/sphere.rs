@@ -1,4 +1,6 @@
 use crate::aabb::Aabb;
+use crate::light::{self, Sampling, Shape};
+use crate::transform::Matrix4;
 use crate::{HitRecord, Material, Ray, Vec3};
 use serde_derive::{Deserialize, Serialize};
 
@@ -15,9 +17,9 @@ fn get_sphere_uv(p: Vec3) -> (f32, f32) {
 pub trait Sphere {
     fn center(&self, time: f32) -> Vec3;
     fn radius(&self) -> f32;
-    fn material(&self) -> Material;
+    fn material(&self) -> &Material;
 
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let center = self.center(ray.time());
         let radius = self.radius();
 
@@ -41,6 +43,8 @@ pub trait Sphere {
                 t: temp,
                 pointing_at,
                 normal,
+                geometric_normal: normal,
+                sampling: Sampling::UnlessSquashed,
                 uv: get_sphere_uv((pointing_at - center) / radius),
                 material: self.material(),
             });
@@ -74,6 +78,12 @@ impl StaticSphere {
             self.center + Vec3::new(self.radius, self.radius, self.radius),
         )
     }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            light::add_emitter(lights, Shape::sphere(self.center, self.radius), matrix);
+        }
+    }
 }
 
 impl Sphere for StaticSphere {
@@ -84,8 +94,8 @@ impl Sphere for StaticSphere {
     fn radius(&self) -> f32 {
         self.radius
     }
-    fn material(&self) -> Material {
-        self.material.clone()
+    fn material(&self) -> &Material {
+        &self.material
     }
 }
 
@@ -123,6 +133,17 @@ impl MovingSphere {
         );
         Aabb::surrounding_box(box0, box1)
     }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            let shape = Shape::Sphere {
+                start: self.start,
+                end: self.end,
+                radius: self.radius,
+            };
+            light::add_emitter(lights, shape, matrix);
+        }
+    }
 }
 
 impl Sphere for MovingSphere {
@@ -135,7 +156,7 @@ impl Sphere for MovingSphere {
     fn radius(&self) -> f32 {
         self.radius
     }
-    fn material(&self) -> Material {
-        self.material.clone()
+    fn material(&self) -> &Material {
+        &self.material
     }
 }
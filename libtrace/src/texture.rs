@@ -1,6 +1,6 @@
 pub use crate::perlin::NoiseTexture;
-use crate::Vec3;
-use image::{DynamicImage, GenericImageView};
+use crate::{load, Vec3};
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
 use serde_derive::{Deserialize, Serialize};
 use std::fmt::{self, Debug};
 
@@ -133,8 +133,13 @@ impl Color {
     }
 }
 
+/// A texture backed by an image. In scene files this is usually
+/// described by the path to the image, relative to the scene file,
+/// which is loaded when the scene is deserialized. Images that weren't
+/// loaded from a file are written out in full, as a PNG.
 #[derive(Clone)]
 pub struct Image {
+    path: Option<String>,
     image: DynamicImage,
 }
 
@@ -144,12 +149,35 @@ impl Debug for Image {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ImageDescription {
+    File {
+        path: String,
+    },
+    Embedded {
+        #[serde(with = "load::encoded")]
+        png: Vec<u8>,
+    },
+}
+
 impl serde::Serialize for Image {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        unimplemented!()
+        use serde::ser::Error;
+        let description = match &self.path {
+            Some(path) => ImageDescription::File { path: path.clone() },
+            None => {
+                let mut png = Vec::new();
+                self.image
+                    .write_to(&mut png, ImageOutputFormat::PNG)
+                    .map_err(S::Error::custom)?;
+                ImageDescription::Embedded { png }
+            }
+        };
+        description.serialize(serializer)
     }
 }
 impl<'de> serde::Deserialize<'de> for Image {
@@ -157,7 +185,18 @@ impl<'de> serde::Deserialize<'de> for Image {
     where
         D: serde::Deserializer<'de>,
     {
-        unimplemented!()
+        use serde::de::Error;
+        match ImageDescription::deserialize(deserializer)? {
+            ImageDescription::File { path } => {
+                let path = load::resolve(&path);
+                Image::open(path.to_string_lossy()).map_err(D::Error::custom)
+            }
+            ImageDescription::Embedded { png } => {
+                let image = image::load_from_memory_with_format(&png, ImageFormat::PNG)
+                    .map_err(D::Error::custom)?;
+                Ok(Image::new(image))
+            }
+        }
     }
 }
 pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
@@ -172,10 +211,22 @@ pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
 }
 impl Image {
     pub fn new(image: DynamicImage) -> Image {
-        Image { image }
+        Image { path: None, image }
     }
 
-    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+    /// Load the image at the given path. Unlike `Image::new`, textures
+    /// created this way remember where they came from, so they're
+    /// written back out to a scene file as the path to the image.
+    pub fn open<P: Into<String>>(path: P) -> image::ImageResult<Image> {
+        let path = path.into();
+        let image = load::image(path.as_ref())?;
+        Ok(Image {
+            path: Some(path),
+            image,
+        })
+    }
+
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Vec3 {
         let i = u * self.image.width() as f32;
         let j = (1. - v) * self.image.height() as f32 - 0.001;
         let i = clamp(i, 0., self.image.width() as f32);
@@ -187,3 +238,27 @@ impl Image {
         Vec3::new(r as f32, g as f32, b as f32).apply(|v| v / 255.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_images_not_loaded_from_files_are_serialized_in_full() {
+        let mut pixels = image::RgbImage::new(3, 2);
+        pixels.put_pixel(0, 0, image::Rgb { data: [255, 0, 0] });
+        pixels.put_pixel(2, 1, image::Rgb { data: [0, 51, 255] });
+        let texture = Image::new(DynamicImage::ImageRgb8(pixels));
+
+        let yaml = serde_yaml::to_string(&texture).unwrap();
+        let loaded: Image = serde_yaml::from_str(&yaml).unwrap();
+        for &(u, v) in &[(0.1, 0.9), (0.9, 0.1), (0.5, 0.5)] {
+            let p = Vec3::new(0., 0., 0.);
+            assert_eq!(loaded.value(u, v, p), texture.value(u, v, p));
+        }
+        assert_eq!(
+            loaded.value(0.9, 0.1, Vec3::new(0., 0., 0.)),
+            Vec3::new(0., 0.2, 1.)
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Splits the image into square tiles, or buckets, that are rendered one
+//! at a time. Pixels close together tend to hit the same objects, so
+//! rendering them together keeps the caches warm, and the order of the
+//! tiles decides how the image fills in while it's rendering.
+
+use crate::scene::Image;
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The order the tiles are rendered in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    #[default]
+    Scanline,
+    /// Spiralling out from the middle of the image, where the subject
+    /// usually is
+    Spiral,
+    /// Along a Hilbert curve, which keeps each tile next to the last
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TileOrder, String> {
+        match s.to_lowercase().as_str() {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tiles {
+    /// How many pixels wide and high each tile is
+    #[serde(default = "Tiles::default_size")]
+    pub size: u32,
+    #[serde(default)]
+    pub order: TileOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Tiles {
+        Tiles {
+            size: Tiles::default_size(),
+            order: TileOrder::default(),
+        }
+    }
+}
+
+impl Tiles {
+    fn default_size() -> u32 {
+        16
+    }
+
+    /// The tiles covering the part of `image` being rendered, in the
+    /// order they should be rendered
+    pub fn split(&self, image: &Image) -> Vec<Tile> {
+        let size = self.size.max(1);
+        let columns = image.width().div_ceil(size);
+        let rows = image.height().div_ceil(size);
+        let tile = |(column, row): (u32, u32)| {
+            let (x, y) = (image.left() + column * size, image.top() + row * size);
+            Tile {
+                x,
+                y,
+                width: size.min(image.left() + image.width() - x),
+                height: size.min(image.top() + image.height() - y),
+            }
+        };
+        let mut cells: Vec<_> = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .collect();
+        match self.order {
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => cells = spiral((columns, rows)),
+            TileOrder::Hilbert => {
+                let side = columns.max(rows).next_power_of_two();
+                cells.sort_by_key(|&cell| hilbert_index(side, cell));
+            }
+        }
+        cells.into_iter().map(tile).collect()
+    }
+}
+
+/// A rectangle of pixels, counting from the top left of the whole image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    /// The pixels in the tile, row by row from its top left
+    pub fn pixels(self) -> impl Iterator<Item = (u32, u32)> {
+        (self.y..self.y + self.height)
+            .flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+    }
+}
+
+/// Every cell of a `columns` by `rows` grid, spiralling outwards from the
+/// middle
+fn spiral((columns, rows): (u32, u32)) -> Vec<(u32, u32)> {
+    let total = (columns * rows) as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = (((columns as i64) - 1) / 2, ((rows as i64) - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut step = 0;
+    while cells.len() < total {
+        // Each run is as long as the last, then one longer
+        let (dx, dy) = directions[step % 4];
+        let run = step / 2 + 1;
+        for _ in 0..run {
+            if x >= 0 && y >= 0 && x < i64::from(columns) && y < i64::from(rows) {
+                cells.push((x as u32, y as u32));
+            }
+            x += dx;
+            y += dy;
+        }
+        step += 1;
+    }
+    cells
+}
+
+/// How far along a Hilbert curve filling a `side` by `side` grid the
+/// cell `(x, y)` is. `side` must be a power of two.
+fn hilbert_index(side: u32, (mut x, mut y): (u32, u32)) -> u64 {
+    let mut index = 0;
+    let mut scale = side / 2;
+    while scale > 0 {
+        let rx = u32::from(x & scale != 0);
+        let ry = u32::from(y & scale != 0);
+        index += u64::from(scale) * u64::from(scale) * u64::from((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve joins up
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        scale /= 2;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_every_pixel_once() {
+        let image: Image = serde_yaml::from_str(
+            "{height: 50, width: 37, samples: 1, slice: {left: 5, top: 10, width: 20, height: 23}}",
+        )
+        .unwrap();
+        for &order in &[TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let tiles = Tiles { size: 8, order }.split(&image);
+            let mut pixels: Vec<_> = tiles.iter().flat_map(|tile| tile.pixels()).collect();
+            pixels.sort_by_key(|&(x, y)| (y, x));
+            let expected: Vec<_> = (10..33)
+                .flat_map(|y| (5..25).map(move |x| (x, y)))
+                .collect();
+            assert_eq!(pixels, expected, "{:?}", order);
+        }
+        // The spiral starts in the middle, and the Hilbert curve only
+        // ever steps to a neighbouring tile
+        let image: Image =
+            serde_yaml::from_str("{height: 64, width: 64, samples: 1, slice: ~}").unwrap();
+        let spiral = Tiles {
+            size: 16,
+            order: TileOrder::Spiral,
+        }
+        .split(&image);
+        assert_eq!((spiral[0].x, spiral[0].y), (16, 16));
+        let hilbert = Tiles {
+            size: 16,
+            order: TileOrder::Hilbert,
+        }
+        .split(&image);
+        for pair in hilbert.windows(2) {
+            let distance = (pair[0].x as i64 - pair[1].x as i64).abs()
+                + (pair[0].y as i64 - pair[1].y as i64).abs();
+            assert_eq!(distance, 16);
+        }
+    }
+}
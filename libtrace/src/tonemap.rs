@@ -0,0 +1,177 @@
+//! Turning the light that reached the film into colors a screen can
+//! show. Radiance is scaled by the exposure, squeezed into [0, 1] by a
+//! tone mapping operator, encoded with the sRGB transfer function, then
+//! quantized to 8 bits.
+
+use crate::Vec3;
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How radiance above 1 is brought back into range
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Operator {
+    /// Anything brighter than 1 is cut off
+    #[default]
+    Clamp,
+    /// Divides the color by one more than its luminance
+    Reinhard,
+    /// John Hable's filmic curve from Uncharted 2
+    Filmic,
+    /// Krzysztof Narkowicz's fit of the ACES reference rendering
+    /// transform
+    Aces,
+}
+
+impl FromStr for Operator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Operator, String> {
+        match s.to_lowercase().as_str() {
+            "clamp" => Ok(Operator::Clamp),
+            "reinhard" => Ok(Operator::Reinhard),
+            "filmic" => Ok(Operator::Filmic),
+            "aces" => Ok(Operator::Aces),
+            _ => Err(format!("unknown tone mapping operator {:?}", s)),
+        }
+    }
+}
+
+impl Operator {
+    fn apply(self, color: Vec3) -> Vec3 {
+        match self {
+            Operator::Clamp => color,
+            Operator::Reinhard => color / (1. + color.luminance()),
+            Operator::Filmic => {
+                const WHITE: f32 = 11.2;
+                // The curve is made for colors about twice as bright
+                let curve = |x: f32| hable(2. * x) / hable(WHITE);
+                Vec3::new(curve(color.x()), curve(color.y()), curve(color.z()))
+            }
+            Operator::Aces => {
+                let curve = |x: f32| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+                // The fit expects the exposure of the reference transform
+                let color = color * 0.6;
+                Vec3::new(curve(color.x()), curve(color.y()), curve(color.z()))
+            }
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.5, 0.1, 0.2, 0.02, 0.3);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ToneMapping {
+    /// How many stops to brighten the image by before tone mapping.
+    /// Negative values darken it.
+    #[serde(default)]
+    pub exposure: f32,
+    #[serde(default)]
+    pub operator: Operator,
+    /// Adds a little noise before quantizing to hide banding in smooth
+    /// gradients
+    #[serde(default)]
+    pub dither: bool,
+}
+
+impl ToneMapping {
+    /// Maps radiance to a linear display color between 0 and 1
+    pub fn map(&self, radiance: Vec3) -> Vec3 {
+        let exposed = radiance * self.exposure.exp2();
+        let exposed = Vec3::new(
+            exposed.x().max(0.),
+            exposed.y().max(0.),
+            exposed.z().max(0.),
+        );
+        let mapped = self.operator.apply(exposed);
+        Vec3::new(
+            mapped.x().clamp(0., 1.),
+            mapped.y().clamp(0., 1.),
+            mapped.z().clamp(0., 1.),
+        )
+    }
+
+    /// The 8 bit sRGB color for the pixel at `(x, y)`. The location
+    /// only matters when dithering, which always adds the same noise
+    /// to the same pixel.
+    pub fn to_rgb8(&self, radiance: Vec3, (x, y): (u32, u32)) -> (u8, u8, u8) {
+        let mapped = self.map(radiance);
+        let quantize = |value: f32, channel: u32| {
+            let noise = if self.dither {
+                dither_noise(x, y, channel)
+            } else {
+                0.
+            };
+            (linear_to_srgb(value) * 255. + 0.5 + noise).clamp(0., 255.) as u8
+        };
+        (
+            quantize(mapped.x(), 0),
+            quantize(mapped.y(), 1),
+            quantize(mapped.z(), 2),
+        )
+    }
+}
+
+/// The sRGB transfer function, for values between 0 and 1
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Triangular noise between -1 and 1 that only depends on the pixel and
+/// channel, so the same image always dithers the same way
+fn dither_noise(x: u32, y: u32, channel: u32) -> f32 {
+    let uniform = |seed: u32| {
+        let mut hash = x
+            .wrapping_mul(0x8da6_b343)
+            .wrapping_add(y.wrapping_mul(0xd816_3841))
+            .wrapping_add(seed.wrapping_mul(0xcb1a_b31f));
+        hash ^= hash >> 16;
+        hash = hash.wrapping_mul(0x7feb_352d);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x846c_a68b);
+        hash ^= hash >> 16;
+        hash as f32 / 4_294_967_296.
+    };
+    uniform(2 * channel) + uniform(2 * channel + 1) - 1.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_keep_bright_lights_in_range() {
+        let light = Vec3::new(15., 15., 15.);
+        let dim = Vec3::new(0.1, 0.1, 0.1);
+        for &operator in &[
+            Operator::Clamp,
+            Operator::Reinhard,
+            Operator::Filmic,
+            Operator::Aces,
+        ] {
+            let tone_mapping = ToneMapping {
+                operator,
+                ..ToneMapping::default()
+            };
+            let bright = tone_mapping.map(light);
+            assert!(bright.x() <= 1. && bright.x() > tone_mapping.map(dim).x());
+        }
+        // Only clamping blows the light out completely
+        let reinhard = ToneMapping {
+            operator: Operator::Reinhard,
+            ..ToneMapping::default()
+        };
+        assert!(reinhard.map(light).x() < 1.);
+        let brighter = ToneMapping {
+            exposure: 1.,
+            ..reinhard.clone()
+        };
+        assert!(brighter.map(dim).x() > reinhard.map(dim).x());
+    }
+}
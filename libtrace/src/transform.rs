@@ -0,0 +1,550 @@
+use crate::aabb::Aabb;
+use crate::light::Shape;
+use crate::{load, HitRecord, Hitable, Ray, Vec3};
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Mul;
+use std::sync::Arc;
+
+/// A 4x4 matrix for affine transformations, stored row by row
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Matrix4([[f32; 4]; 4]);
+
+impl Default for Matrix4 {
+    fn default() -> Matrix4 {
+        Matrix4::identity()
+    }
+}
+
+impl Matrix4 {
+    pub fn new(rows: [[f32; 4]; 4]) -> Matrix4 {
+        Matrix4(rows)
+    }
+
+    pub fn identity() -> Matrix4 {
+        Matrix4([
+            [1., 0., 0., 0.],
+            [0., 1., 0., 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn translate(offset: Vec3) -> Matrix4 {
+        let (x, y, z) = offset.to_tuple();
+        Matrix4([
+            [1., 0., 0., x],
+            [0., 1., 0., y],
+            [0., 0., 1., z],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn scale(scale: Vec3) -> Matrix4 {
+        let (x, y, z) = scale.to_tuple();
+        Matrix4([
+            [x, 0., 0., 0.],
+            [0., y, 0., 0.],
+            [0., 0., z, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    /// Rotation around the x axis, in degrees
+    pub fn rotate_x(degrees: f32) -> Matrix4 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix4([
+            [1., 0., 0., 0.],
+            [0., cos, -sin, 0.],
+            [0., sin, cos, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    /// Rotation around the y axis, in degrees
+    pub fn rotate_y(degrees: f32) -> Matrix4 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix4([
+            [cos, 0., sin, 0.],
+            [0., 1., 0., 0.],
+            [-sin, 0., cos, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    /// Rotation around the z axis, in degrees
+    pub fn rotate_z(degrees: f32) -> Matrix4 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Matrix4([
+            [cos, -sin, 0., 0.],
+            [sin, cos, 0., 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut out = [[0.; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.0[j][i];
+            }
+        }
+        Matrix4(out)
+    }
+
+    /// Inverts the matrix with Gauss-Jordan elimination. Returns `None`
+    /// if the matrix is singular.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut m = self.0;
+        let mut inv = Matrix4::identity().0;
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&a, &b| m[a][col].abs().partial_cmp(&m[b][col].abs()).unwrap())
+                .unwrap();
+            if m[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1. / m[col][col];
+            for j in 0..4 {
+                m[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                if row == col {
+                    continue;
+                }
+                let factor = m[row][col];
+                for j in 0..4 {
+                    m[row][j] -= factor * m[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+        Some(Matrix4(inv))
+    }
+
+    #[inline]
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        let m = &self.0;
+        let (x, y, z) = p.to_tuple();
+        Vec3::new(
+            m[0][0] * x + m[0][1] * y + m[0][2] * z + m[0][3],
+            m[1][0] * x + m[1][1] * y + m[1][2] * z + m[1][3],
+            m[2][0] * x + m[2][1] * y + m[2][2] * z + m[2][3],
+        )
+    }
+
+    /// Transforms a direction, which unlike a point isn't affected by
+    /// translation
+    #[inline]
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.0;
+        let (x, y, z) = v.to_tuple();
+        Vec3::new(
+            m[0][0] * x + m[0][1] * y + m[0][2] * z,
+            m[1][0] * x + m[1][1] * y + m[1][2] * z,
+            m[2][0] * x + m[2][1] * y + m[2][2] * z,
+        )
+    }
+
+    /// Transforms a surface normal by the transpose of this matrix.
+    /// Call this on the *inverse* of the matrix that transforms the
+    /// surface to keep the normal perpendicular to it.
+    #[inline]
+    pub fn transform_normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.0;
+        let (x, y, z) = n.to_tuple();
+        Vec3::new(
+            m[0][0] * x + m[1][0] * y + m[2][0] * z,
+            m[0][1] * x + m[1][1] * y + m[2][1] * z,
+            m[0][2] * x + m[1][2] * y + m[2][2] * z,
+        )
+    }
+
+    /// If this matrix scales every direction by the same amount, that
+    /// amount. Shapes like spheres keep their shape under these
+    /// matrices.
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let axes = [
+            self.transform_vector(Vec3::new(1., 0., 0.)),
+            self.transform_vector(Vec3::new(0., 1., 0.)),
+            self.transform_vector(Vec3::new(0., 0., 1.)),
+        ];
+        let scale = axes[0].length();
+        let tolerance = 1e-4 * scale.max(1.);
+        for (i, axis) in axes.iter().enumerate() {
+            if (axis.length() - scale).abs() > tolerance
+                || axis.dot(axes[(i + 1) % 3]).abs() > tolerance * scale
+            {
+                return None;
+            }
+        }
+        Some(scale)
+    }
+
+    /// Transforms an axis aligned box, returning a box around the result
+    pub fn transform_box(&self, bbox: &Aabb) -> Aabb {
+        let (min, max) = (bbox.min(), bbox.max());
+        let corner = |i: usize| {
+            Vec3::new(
+                if i & 1 == 0 { min.x() } else { max.x() },
+                if i & 2 == 0 { min.y() } else { max.y() },
+                if i & 4 == 0 { min.z() } else { max.z() },
+            )
+        };
+        let first = self.transform_point(corner(0));
+        (1..8).fold(Aabb::new(first, first), |bbox, i| {
+            let p = self.transform_point(corner(i));
+            Aabb::surrounding_box(bbox, Aabb::new(p, p))
+        })
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        let mut out = [[0.; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.0[i][k] * rhs.0[k][j]).sum();
+            }
+        }
+        Matrix4(out)
+    }
+}
+
+/// A friendlier way of describing a transformation in scene files.
+/// Things are scaled, then rotated about the x, y and z axes in that
+/// order, and then translated.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Placement {
+    #[serde(default)]
+    pub translate: Vec3,
+    /// Rotation around each axis, in degrees
+    #[serde(default)]
+    pub rotate: Vec3,
+    #[serde(default = "Placement::default_scale")]
+    pub scale: Vec3,
+}
+
+impl Default for Placement {
+    fn default() -> Placement {
+        Placement {
+            translate: Vec3::default(),
+            rotate: Vec3::default(),
+            scale: Placement::default_scale(),
+        }
+    }
+}
+
+impl Placement {
+    fn default_scale() -> Vec3 {
+        Vec3::new(1., 1., 1.)
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        Matrix4::translate(self.translate)
+            * Matrix4::rotate_z(self.rotate.z())
+            * Matrix4::rotate_y(self.rotate.y())
+            * Matrix4::rotate_x(self.rotate.x())
+            * Matrix4::scale(self.scale)
+    }
+}
+
+/// Objects that are defined once in a scene and then placed any number
+/// of times by name, with a `Transform`. Every placement shares the
+/// same object, and it's only written out once when the scene is
+/// serialized. Instances can place the ones defined before them.
+#[derive(Debug, Clone, Default)]
+pub struct Instances(Vec<(String, Arc<Hitable>)>);
+
+impl Instances {
+    pub fn get(&self, name: &str) -> Option<&Arc<Hitable>> {
+        self.0
+            .iter()
+            .find(|(instance, _)| instance == name)
+            .map(|(_, hitable)| hitable)
+    }
+}
+
+impl serde::Serialize for Instances {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, hitable) in &self.0 {
+            map.serialize_entry(name, hitable)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Instances {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Instances, D::Error> {
+        struct InstancesVisitor;
+
+        impl<'de> Visitor<'de> for InstancesVisitor {
+            type Value = Instances;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of names to objects")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Instances, A::Error> {
+                let mut instances = Vec::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    let hitable: Arc<Hitable> = map.next_value()?;
+                    // Each one is defined as soon as it's loaded, so the
+                    // next can use it
+                    if !load::define_instance(&name, &hitable) {
+                        return Err(de::Error::custom(format!(
+                            "there's more than one instance called {:?}",
+                            name
+                        )));
+                    }
+                    instances.push((name, hitable));
+                }
+                Ok(Instances(instances))
+            }
+        }
+
+        deserializer.deserialize_map(InstancesVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct TransformDescription {
+    /// Applied before `placement`, if present
+    #[serde(default)]
+    matrix: Option<Matrix4>,
+    #[serde(flatten)]
+    placement: Placement,
+    /// The name of one of the scene's instances to place, instead of
+    /// `inner`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inner: Option<Arc<Hitable>>,
+}
+
+/// Places another hitable somewhere else in the world. The hitable
+/// being transformed is shared, so one object can be instanced any
+/// number of times without being copied. In scene files, the objects
+/// to share are defined in the scene's `instances`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "TransformDescription", into = "TransformDescription")]
+pub struct Transform {
+    matrix: Matrix4,
+    inverse: Matrix4,
+    inner: Arc<Hitable>,
+    /// The name of the instance being placed, if it's one of the scene's
+    instance: Option<String>,
+    /// Whether the matrix scales every axis by the same amount
+    uniform: bool,
+}
+
+impl TryFrom<TransformDescription> for Transform {
+    type Error = String;
+
+    fn try_from(description: TransformDescription) -> Result<Transform, String> {
+        let inner = match (description.inner, &description.instance) {
+            (Some(inner), None) => inner,
+            (None, Some(name)) => load::instance(name).ok_or_else(|| {
+                format!(
+                    "there's no instance called {:?} defined before it's used",
+                    name
+                )
+            })?,
+            _ => return Err("a transform needs either an inner object or an instance".into()),
+        };
+        let matrix = description.placement.matrix() * description.matrix.unwrap_or_default();
+        let mut transform =
+            Transform::new(matrix, inner).ok_or("transformation matrices must be invertible")?;
+        transform.instance = description.instance;
+        Ok(transform)
+    }
+}
+
+impl From<Transform> for TransformDescription {
+    fn from(transform: Transform) -> TransformDescription {
+        let inner = match transform.instance {
+            Some(_) => None,
+            None => Some(transform.inner),
+        };
+        TransformDescription {
+            matrix: Some(transform.matrix),
+            placement: Placement::default(),
+            instance: transform.instance,
+            inner,
+        }
+    }
+}
+
+impl Transform {
+    /// Returns `None` if the matrix can't be inverted
+    pub fn new<H: Into<Arc<Hitable>>>(matrix: Matrix4, inner: H) -> Option<Transform> {
+        Some(Transform {
+            matrix,
+            inverse: matrix.inverse()?,
+            inner: inner.into(),
+            instance: None,
+            uniform: matrix.uniform_scale().is_some(),
+        })
+    }
+
+    pub fn place<H: Into<Arc<Hitable>>>(placement: &Placement, inner: H) -> Option<Transform> {
+        Transform::new(placement.matrix(), inner)
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        // the direction isn't normalized, so t means the same
+        // thing in both spaces
+        let local_ray = Ray::new(
+            self.inverse.transform_point(ray.origin()),
+            self.inverse.transform_vector(ray.direction()),
+            ray.time(),
+        );
+        let mut hit_record = self.inner.hit(&local_ray, t_min, t_max)?;
+        hit_record.pointing_at = self.matrix.transform_point(hit_record.pointing_at);
+        hit_record.normal = self
+            .inverse
+            .transform_normal(hit_record.normal)
+            .into_normalized();
+        hit_record.geometric_normal = self
+            .inverse
+            .transform_normal(hit_record.geometric_normal)
+            .into_normalized();
+        hit_record.sampling = hit_record.sampling.transformed(self.uniform);
+        Some(hit_record)
+    }
+
+    pub fn bounding_box(&self, time: (f32, f32)) -> Aabb {
+        self.matrix.transform_box(&self.inner.bounding_box(time))
+    }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        self.inner.emitters(&(*matrix * self.matrix), lights);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::scene::Scene;
+    use crate::texture::Color;
+    use crate::StaticSphere;
+
+    #[test]
+    fn test_inverse() {
+        let placement = Placement {
+            translate: Vec3::new(1., 2., 3.),
+            rotate: Vec3::new(10., 20., 30.),
+            scale: Vec3::new(2., 3., 4.),
+        };
+        let matrix = placement.matrix();
+        let identity = matrix * matrix.inverse().unwrap();
+        for i in 0..4 {
+            for j in 0..4 {
+                let expected = if i == j { 1. } else { 0. };
+                assert!((identity.0[i][j] - expected).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_instances_share_inner() {
+        let sphere: Arc<Hitable> =
+            Arc::new(StaticSphere::new(1., (0., 0., 0.), Lambertian::new(Color::new(0.5))).into());
+        let placement = |x| Placement {
+            translate: Vec3::new(x, 0., 0.),
+            scale: Vec3::new(1., 2., 1.),
+            ..Placement::default()
+        };
+        let left = Transform::place(&placement(-5.), sphere.clone()).unwrap();
+        let right = Transform::place(&placement(5.), sphere.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&sphere), 3);
+
+        let ray = Ray::new(Vec3::new(5., 0., 10.), Vec3::new(0., 0., -1.), 0.);
+        assert!(left.hit(&ray, 0.001, f32::MAX).is_none());
+        let hit = right.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.t - 9.).abs() < 1e-4);
+        assert!((hit.pointing_at - Vec3::new(5., 0., 1.)).length() < 1e-4);
+        assert!((hit.normal - Vec3::new(0., 0., 1.)).length() < 1e-4);
+
+        // the top of the stretched sphere
+        let ray = Ray::new(Vec3::new(5., 10., 0.), Vec3::new(0., -1., 0.), 0.);
+        let hit = right.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.pointing_at - Vec3::new(5., 2., 0.)).length() < 1e-4);
+
+        let bbox = right.bounding_box((0., 1.));
+        assert!((bbox.min() - Vec3::new(4., -2., -1.)).length() < 1e-4);
+        assert!((bbox.max() - Vec3::new(6., 2., 1.)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_scene_instances_are_shared() {
+        let yaml = r#"
+image: {height: 10, width: 20, samples: 1, slice: ~}
+camera: {look_from: [0.0, 0.0, -1.0], look_at: [0.0, 0.0, 0.0], aperture: 0.0, fov: 40.0}
+instances:
+  ball:
+    type: StaticSphere
+    radius: 1.0
+    center: [0.0, 0.0, 0.0]
+    material: {type: Lambertian, albedo: {type: Color, color: [0.5, 0.5, 0.5]}}
+  pair:
+    type: List
+    items:
+      - {type: Transform, instance: ball, translate: [-1.0, 0.0, 0.0]}
+      - {type: Transform, instance: ball, translate: [1.0, 0.0, 0.0]}
+objects:
+  type: List
+  items:
+    - {type: Transform, instance: ball}
+    - {type: Transform, instance: pair, translate: [0.0, 5.0, 0.0]}
+"#;
+        let inner = |hitable: &Hitable| match hitable {
+            Hitable::Transform(transform) => Arc::clone(&transform.inner),
+            other => panic!("expected a transform, got {:?}", other),
+        };
+        let items = |hitable: &Hitable| match hitable {
+            Hitable::List(list) => list.items().to_vec(),
+            other => panic!("expected a list, got {:?}", other),
+        };
+        let scene: Scene = serde_yaml::from_str(yaml).unwrap();
+        let ball = scene.instances.get("ball").unwrap();
+        let objects = items(&scene.objects);
+        assert!(Arc::ptr_eq(&inner(&objects[0]), ball));
+        let pair = inner(&objects[1]);
+        assert!(Arc::ptr_eq(&pair, scene.instances.get("pair").unwrap()));
+        for placed in items(&pair) {
+            assert!(Arc::ptr_eq(&inner(&placed), ball));
+        }
+
+        // Written out, the ball is only described once
+        let yaml = serde_yaml::to_string(&scene).unwrap();
+        assert_eq!(yaml.matches("StaticSphere").count(), 1);
+        let reloaded: Scene = serde_yaml::from_str(&yaml).unwrap();
+        let objects = items(&reloaded.objects);
+        assert!(Arc::ptr_eq(
+            &inner(&objects[0]),
+            reloaded.instances.get("ball").unwrap()
+        ));
+
+        // Nothing leaks into the next scene
+        let header = yaml.split("instances:").next().unwrap();
+        let next = format!("{}objects: {{type: Transform, instance: ball}}", header);
+        let err = serde_yaml::from_str::<Scene>(&next)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no instance called \"ball\""), "{}", err);
+    }
+}
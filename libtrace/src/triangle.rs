@@ -0,0 +1,573 @@
+use crate::aabb::Aabb;
+use crate::light::{self, Sampling, Shape};
+use crate::transform::Matrix4;
+use crate::{HitRecord, Material, Ray, Vec3};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// How much to pad the bounding box of a triangle that lies flat
+/// along an axis, so the box still has some volume to hit.
+const BBOX_PADDING: f32 = 0.0001;
+
+/// The maximum number of faces that will be stored in a leaf of a
+/// mesh's bounding volume hierarchy.
+const MAX_FACES_PER_LEAF: usize = 4;
+
+/// Intersects a ray with the triangle `p0 p1 p2` using the
+/// Möller–Trumbore algorithm. On a hit, returns `t` along with the
+/// barycentric coordinates of the hit for `p1` and `p2`.
+#[inline]
+fn intersect(
+    (p0, p1, p2): (Vec3, Vec3, Vec3),
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, f32, f32)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let pvec = ray.direction().cross(edge2);
+    let det = edge1.dot(pvec);
+    // Only a ray in the plane of the triangle misses outright. A ray
+    // that's nearly parallel gives huge barycentrics that fail the
+    // checks below, however small the triangle.
+    if det == 0. {
+        return None;
+    }
+    let inv_det = 1. / det;
+
+    let tvec = ray.origin() - p0;
+    let u = tvec.dot(pvec) * inv_det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let v = ray.direction().dot(qvec) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+
+    let t = edge2.dot(qvec) * inv_det;
+    if t < t_min || t > t_max {
+        return None;
+    }
+    Some((t, u, v))
+}
+
+#[inline]
+fn interpolate<T>(values: [T; 3], (b1, b2): (f32, f32)) -> T
+where
+    T: std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+{
+    let [a, b, c] = values;
+    a * (1. - b1 - b2) + b * b1 + c * b2
+}
+
+fn bounding_box(points: &[Vec3]) -> Aabb {
+    let init = Aabb::new(points[0], points[0]);
+    let bbox = points[1..].iter().fold(init, |bbox, &point| {
+        Aabb::surrounding_box(bbox, Aabb::new(point, point))
+    });
+    let padding = Vec3::from(BBOX_PADDING);
+    Aabb::new(bbox.min() - padding, bbox.max() + padding)
+}
+
+/// Builds the hit record for a hit on a triangle. `normals` and `uvs`
+/// are optional per-vertex attributes; without normals the face normal
+/// is used, and without uvs the barycentric coordinates are.
+#[inline]
+fn hit_record<'a>(
+    ray: &Ray,
+    (t, b1, b2): (f32, f32, f32),
+    (p0, p1, p2): (Vec3, Vec3, Vec3),
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[(f32, f32); 3]>,
+    material: &'a Material,
+) -> HitRecord<'a> {
+    let geometric_normal = (p1 - p0).cross(p2 - p0).into_normalized();
+    let normal = match normals {
+        Some(normals) => interpolate(normals, (b1, b2)).into_normalized(),
+        None => geometric_normal,
+    };
+    let uv = match uvs {
+        Some([uv0, uv1, uv2]) => (
+            interpolate([uv0.0, uv1.0, uv2.0], (b1, b2)),
+            interpolate([uv0.1, uv1.1, uv2.1], (b1, b2)),
+        ),
+        None => (b1, b2),
+    };
+    HitRecord {
+        t,
+        pointing_at: ray.point_at(t),
+        normal,
+        geometric_normal,
+        sampling: Sampling::Always,
+        uv,
+        material,
+    }
+}
+
+/// A single corner of a triangle
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Vertex {
+    pub position: Vec3,
+    #[serde(default)]
+    pub normal: Option<Vec3>,
+    #[serde(default)]
+    pub uv: Option<(f32, f32)>,
+}
+
+impl From<Vec3> for Vertex {
+    fn from(position: Vec3) -> Vertex {
+        Vertex {
+            position,
+            normal: None,
+            uv: None,
+        }
+    }
+}
+
+/// A standalone triangle. The front face is the one where the
+/// vertices wind counter-clockwise.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Triangle {
+    vertices: [Vertex; 3],
+    material: Material,
+}
+
+impl Triangle {
+    pub fn new<V: Into<Vertex>, M: Into<Material>>(v0: V, v1: V, v2: V, material: M) -> Triangle {
+        Triangle {
+            vertices: [v0.into(), v1.into(), v2.into()],
+            material: material.into(),
+        }
+    }
+
+    fn positions(&self) -> (Vec3, Vec3, Vec3) {
+        let [v0, v1, v2] = &self.vertices;
+        (v0.position, v1.position, v2.position)
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let positions = self.positions();
+        let hit = intersect(positions, ray, t_min, t_max)?;
+        let [v0, v1, v2] = &self.vertices;
+        let normals = match (v0.normal, v1.normal, v2.normal) {
+            (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+            _ => None,
+        };
+        let uvs = match (v0.uv, v1.uv, v2.uv) {
+            (Some(uv0), Some(uv1), Some(uv2)) => Some([uv0, uv1, uv2]),
+            _ => None,
+        };
+        Some(hit_record(
+            ray,
+            hit,
+            positions,
+            normals,
+            uvs,
+            &self.material,
+        ))
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let (p0, p1, p2) = self.positions();
+        bounding_box(&[p0, p1, p2])
+    }
+
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            let (p0, p1, p2) = self.positions();
+            light::add_emitter(lights, Shape::Triangle([p0, p1, p2]), matrix);
+        }
+    }
+}
+
+/// The serialized form of a `TriangleMesh`. The acceleration
+/// structure is rebuilt when a mesh is deserialized rather than being
+/// stored in the scene.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MeshDescription {
+    positions: Vec<Vec3>,
+    #[serde(default)]
+    normals: Vec<Vec3>,
+    #[serde(default)]
+    uvs: Vec<(f32, f32)>,
+    faces: Vec<[u32; 3]>,
+    material: Material,
+}
+
+/// A mesh of triangles that share vertices. Each face is three indices
+/// into `positions`, and if present, `normals` and `uvs` are indexed
+/// the same way.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "MeshDescription", into = "MeshDescription")]
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    faces: Vec<[u32; 3]>,
+    material: Material,
+    nodes: Vec<MeshNode>,
+}
+
+#[derive(Debug, Clone)]
+enum MeshNodeKind {
+    /// A range of `faces`
+    Leaf { start: usize, end: usize },
+    /// The index of the right child. The left child always directly
+    /// follows its parent.
+    Interior { right: usize },
+}
+
+#[derive(Debug, Clone)]
+struct MeshNode {
+    bbox: Aabb,
+    kind: MeshNodeKind,
+}
+
+impl MeshDescription {
+    fn validate(&self) -> Result<(), String> {
+        if !self.normals.is_empty() && self.normals.len() != self.positions.len() {
+            return Err("a mesh needs either no normals or one for every vertex".into());
+        }
+        if !self.uvs.is_empty() && self.uvs.len() != self.positions.len() {
+            return Err("a mesh needs either no uvs or one for every vertex".into());
+        }
+        let num_positions = self.positions.len();
+        if let Some(face) = self
+            .faces
+            .iter()
+            .find(|face| face.iter().any(|&i| i as usize >= num_positions))
+        {
+            return Err(format!(
+                "the mesh face {:?} refers to a vertex that doesn't exist",
+                face
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<MeshDescription> for TriangleMesh {
+    type Error = String;
+
+    fn try_from(mesh: MeshDescription) -> Result<TriangleMesh, String> {
+        mesh.validate()?;
+        Ok(TriangleMesh::new(
+            mesh.positions,
+            mesh.normals,
+            mesh.uvs,
+            mesh.faces,
+            mesh.material,
+        ))
+    }
+}
+
+impl From<TriangleMesh> for MeshDescription {
+    fn from(mesh: TriangleMesh) -> MeshDescription {
+        MeshDescription {
+            positions: mesh.positions,
+            normals: mesh.normals,
+            uvs: mesh.uvs,
+            faces: mesh.faces,
+            material: mesh.material,
+        }
+    }
+}
+
+impl TriangleMesh {
+    /// Creates a new mesh. `normals` and `uvs` may be empty, otherwise
+    /// they must be the same length as `positions`.
+    ///
+    /// This panics if that isn't the case, or if a face refers to a
+    /// vertex that doesn't exist.
+    pub fn new<M: Into<Material>>(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        faces: Vec<[u32; 3]>,
+        material: M,
+    ) -> TriangleMesh {
+        let mesh = MeshDescription {
+            positions,
+            normals,
+            uvs,
+            faces,
+            material: material.into(),
+        };
+        if let Err(message) = mesh.validate() {
+            panic!("{}", message);
+        }
+
+        let mut mesh = TriangleMesh {
+            positions: mesh.positions,
+            normals: mesh.normals,
+            uvs: mesh.uvs,
+            faces: mesh.faces,
+            material: mesh.material,
+            nodes: Vec::new(),
+        };
+        if !mesh.faces.is_empty() {
+            let num_faces = mesh.faces.len();
+            mesh.build_node(0, num_faces);
+        }
+        mesh
+    }
+
+    pub fn num_faces(&self) -> usize {
+        self.faces.len()
+    }
+
+    #[inline]
+    fn face_positions(&self, face: [u32; 3]) -> (Vec3, Vec3, Vec3) {
+        (
+            self.positions[face[0] as usize],
+            self.positions[face[1] as usize],
+            self.positions[face[2] as usize],
+        )
+    }
+
+    fn face_centroid(&self, face: [u32; 3]) -> Vec3 {
+        let (p0, p1, p2) = self.face_positions(face);
+        (p0 + p1 + p2) / 3.
+    }
+
+    /// Recursively builds the hierarchy for `faces[start..end]`,
+    /// reordering the faces so every node covers a contiguous range.
+    fn build_node(&mut self, start: usize, end: usize) -> usize {
+        let points: Vec<Vec3> = self.faces[start..end]
+            .iter()
+            .flat_map(|&face| {
+                let (p0, p1, p2) = self.face_positions(face);
+                vec![p0, p1, p2]
+            })
+            .collect();
+        let bbox = bounding_box(&points);
+
+        let index = self.nodes.len();
+        if end - start <= MAX_FACES_PER_LEAF {
+            self.nodes.push(MeshNode {
+                bbox,
+                kind: MeshNodeKind::Leaf { start, end },
+            });
+            return index;
+        }
+
+        // split on the longest axis of the box around the face centroids
+        let centroids: Vec<Vec3> = self.faces[start..end]
+            .iter()
+            .map(|&face| self.face_centroid(face))
+            .collect();
+        let centroid_bounds = bounding_box(&centroids);
+        let extent = centroid_bounds.max() - centroid_bounds.min();
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+            0
+        } else if extent.y() > extent.z() {
+            1
+        } else {
+            2
+        };
+
+        let mid = (start + end) / 2;
+        let positions = &self.positions;
+        let centroid = |face: &[u32; 3]| {
+            let sum: f32 = face
+                .iter()
+                .map(|&i| positions[i as usize].as_slice()[axis])
+                .sum();
+            sum / 3.
+        };
+        self.faces[start..end]
+            .select_nth_unstable_by(mid - start, |a, b| centroid(a).total_cmp(&centroid(b)));
+
+        self.nodes.push(MeshNode {
+            bbox,
+            kind: MeshNodeKind::Interior { right: 0 },
+        });
+        self.build_node(start, mid);
+        let right = self.build_node(mid, end);
+        self.nodes[index].kind = MeshNodeKind::Interior { right };
+        index
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut closest: Option<((f32, f32, f32), [u32; 3])> = None;
+        let mut closest_so_far = t_max;
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bbox.hit(ray, t_min, closest_so_far) {
+                continue;
+            }
+            match node.kind {
+                MeshNodeKind::Leaf { start, end } => {
+                    for &face in &self.faces[start..end] {
+                        let positions = self.face_positions(face);
+                        if let Some(hit) = intersect(positions, ray, t_min, closest_so_far) {
+                            closest_so_far = hit.0;
+                            closest = Some((hit, face));
+                        }
+                    }
+                }
+                MeshNodeKind::Interior { right } => {
+                    stack.push(right);
+                    stack.push(index + 1);
+                }
+            }
+        }
+
+        let (hit, face) = closest?;
+        let [i0, i1, i2] = face;
+        let (i0, i1, i2) = (i0 as usize, i1 as usize, i2 as usize);
+        let normals = if self.normals.is_empty() {
+            None
+        } else {
+            Some([self.normals[i0], self.normals[i1], self.normals[i2]])
+        };
+        let uvs = if self.uvs.is_empty() {
+            None
+        } else {
+            Some([self.uvs[i0], self.uvs[i1], self.uvs[i2]])
+        };
+        Some(hit_record(
+            ray,
+            hit,
+            self.face_positions(face),
+            normals,
+            uvs,
+            &self.material,
+        ))
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(root) => root.bbox,
+            None => Aabb::new(Vec3::default(), Vec3::default()),
+        }
+    }
+
+    /// Every face of an emissive mesh is a separate light
+    pub fn emitters(&self, matrix: &Matrix4, lights: &mut Vec<Shape>) {
+        if self.material.is_emissive() {
+            for &face in &self.faces {
+                let (p0, p1, p2) = self.face_positions(face);
+                light::add_emitter(lights, Shape::Triangle([p0, p1, p2]), matrix);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::Color;
+
+    fn quad() -> TriangleMesh {
+        TriangleMesh::new(
+            vec![
+                Vec3::new(0., 0., 0.),
+                Vec3::new(1., 0., 0.),
+                Vec3::new(1., 1., 0.),
+                Vec3::new(0., 1., 0.),
+            ],
+            vec![],
+            vec![(0., 0.), (1., 0.), (1., 1.), (0., 1.)],
+            vec![[0, 1, 2], [0, 2, 3]],
+            Lambertian::new(Color::new(0.5)),
+        )
+    }
+
+    #[test]
+    fn test_mesh_hit() {
+        let mesh = quad();
+        let ray = Ray::new(Vec3::new(0.25, 0.75, 1.), Vec3::new(0., 0., -1.), 0.);
+        let hit = mesh.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.t - 1.).abs() < 1e-5);
+        assert!((hit.uv.0 - 0.25).abs() < 1e-5);
+        assert!((hit.uv.1 - 0.75).abs() < 1e-5);
+        assert_eq!(hit.normal, Vec3::new(0., 0., 1.));
+
+        let miss = Ray::new(Vec3::new(1.5, 0.5, 1.), Vec3::new(0., 0., -1.), 0.);
+        assert!(mesh.hit(&miss, 0.001, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn test_large_mesh_hit() {
+        // a 16x16 grid of quads in the z = 0 plane, enough to need
+        // several levels of hierarchy
+        let n = 16;
+        let mut positions = Vec::new();
+        for y in 0..=n {
+            for x in 0..=n {
+                positions.push(Vec3::new(x as f32, y as f32, 0.));
+            }
+        }
+        let mut faces = Vec::new();
+        let index = |x: u32, y: u32| y * (n + 1) + x;
+        for y in 0..n {
+            for x in 0..n {
+                faces.push([index(x, y), index(x + 1, y), index(x + 1, y + 1)]);
+                faces.push([index(x, y), index(x + 1, y + 1), index(x, y + 1)]);
+            }
+        }
+        let mesh = TriangleMesh::new(
+            positions,
+            vec![],
+            vec![],
+            faces,
+            Lambertian::new(Color::new(0.5)),
+        );
+        for &(x, y) in &[(0.1, 0.1), (7.3, 12.9), (15.9, 0.5), (8.5, 8.5)] {
+            let ray = Ray::new(Vec3::new(x, y, 2.), Vec3::new(0., 0., -1.), 0.);
+            let hit = mesh.hit(&ray, 0.001, f32::MAX).unwrap();
+            assert!((hit.pointing_at - Vec3::new(x, y, 0.)).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_tiny_triangle_hit() {
+        let scale = 1e-4;
+        let triangle = Triangle::new(
+            Vec3::new(0., 0., 0.),
+            Vec3::new(scale, 0., 0.),
+            Vec3::new(0., scale, 0.),
+            Lambertian::new(Color::new(0.5)),
+        );
+        let ray = Ray::new(
+            Vec3::new(0.25 * scale, 0.25 * scale, 1.),
+            Vec3::new(0., 0., -1.),
+            0.,
+        );
+        let hit = triangle.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.t - 1.).abs() < 1e-5);
+        assert!((hit.uv.0 - 0.25).abs() < 1e-3);
+
+        let miss = Ray::new(Vec3::new(scale, scale, 1.), Vec3::new(0., 0., -1.), 0.);
+        assert!(triangle.hit(&miss, 0.001, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn test_triangle_interpolates_normals() {
+        let normal = Vec3::new(0., 1., 1.).into_normalized();
+        let vertex = |x, y| Vertex {
+            position: Vec3::new(x, y, 0.),
+            normal: Some(normal),
+            uv: None,
+        };
+        let triangle = Triangle::new(
+            vertex(0., 0.),
+            vertex(1., 0.),
+            vertex(0., 1.),
+            Lambertian::new(Color::new(0.5)),
+        );
+        let ray = Ray::new(Vec3::new(0.2, 0.2, 1.), Vec3::new(0., 0., -1.), 0.);
+        let hit = triangle.hit(&ray, 0.001, f32::MAX).unwrap();
+        assert!((hit.normal - normal).length() < 1e-5);
+    }
+}
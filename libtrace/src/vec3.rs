@@ -41,9 +41,8 @@ impl Vec3 {
         &self.0
     }
     /// Creates a random vector
-    pub fn random_in_unit_circle() -> Vec3 {
+    pub fn random_in_unit_circle(rng: &mut crate::random::TraceRng) -> Vec3 {
         use rand::Rng;
-        let mut rng = rand::thread_rng();
         loop {
             let vec = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 2. - Vec3::new(1., 1., 1.);
             if vec.squared_length() <= 1. {
@@ -67,6 +66,8 @@ impl Vec3 {
     /// The difference between this and the checked version is that this
     /// doesn't do the bounds checking that the other one does.
     ///
+    /// # Safety
+    ///
     /// If the slice has a length < 3, this will panic
     pub unsafe fn from_slice_unchecked(slice: &[f32]) -> Vec3 {
         Vec3([slice[0], slice[1], slice[2]])
@@ -166,6 +167,12 @@ impl Vec3 {
         &mut self.0[2]
     }
 
+    /// How bright this color looks, using the Rec. 709 weights
+    #[inline]
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x() + 0.7152 * self.y() + 0.0722 * self.z()
+    }
+
     #[inline]
     pub fn dot(mut self, other: Vec3) -> f32 {
         *self.mut_x() *= other.x();
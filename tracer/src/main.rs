@@ -1,165 +1,18 @@
-use std::f32;
-
-use libtrace::{
-    material::{Dialectric, Diffuse, Lambertian, Material, Metal},
-    texture, BvhNode, Hitable, MovingSphere, StaticSphere, Vec3,
-};
-
-use image::flat::NormalForm::ColumnMajorPacked;
 use indicatif::{ProgressBar, ProgressStyle};
-use libtrace::texture::{CheckerBoard, Color, Image, NoiseTexture, Texture};
-use rand::prelude::*;
-use rayon::prelude::*;
+use libtrace::checkpoint::{self, Checkpoint};
+use libtrace::exr::Precision;
+use libtrace::output::{write_atomically, Format};
+use libtrace::renderer::{Renderer, TimeBudget};
+use libtrace::scene::{Image, Scene};
+use libtrace::{Film, Hitable, Lights, Vec3};
 use std::error::Error;
 use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
-#[allow(unused)]
-fn two_spheres() -> Hitable {
-    use texture::Color;
-    let checker: Texture =
-        CheckerBoard::new(10., Color::new((0.2, 0.3, 0.1)), Color::new(0.9)).into();
-    let world: Vec<Hitable> = vec![
-        StaticSphere::new(10., (0., -10., 0.), Lambertian::new(checker.clone())).into(),
-        StaticSphere::new(2.5, (0., 2.5, 0.), Lambertian::new(checker.clone())).into(),
-    ];
-    Hitable::List(world.into())
-}
-
-fn earth_sphere() -> Hitable {
-    let text: Texture = Image::new(image::open("earth.jpg").unwrap()).into();
-    StaticSphere::new(10., (0., 0., 0.), Lambertian::new(text)).into()
-}
-
-fn simple_light() -> Hitable {
-    use libtrace::rect::XYRect;
-    let text: Texture = NoiseTexture::new(4.).into();
-    let world: Vec<Hitable> = vec![
-        StaticSphere::new(1000., (0., -1000., 0.), Lambertian::new(text.clone())).into(),
-        StaticSphere::new(2., (0., 2., 0.), Lambertian::new(text.clone())).into(),
-        XYRect::new(
-            3.,
-            5.,
-            1.,
-            3.,
-            -2.,
-            Diffuse::new(CheckerBoard::new(
-                8.,
-                Color::new(4.),
-                Color::new(Vec3::new(1.0, 0.4, 0.6) * 4.),
-            )),
-        )
-        .into(),
-    ];
-    Hitable::List(world.into())
-}
-
-fn two_perlin_spheres() -> Hitable {
-    let text: Texture = NoiseTexture::new(4.).into();
-    //    let text: Texture = Color::new(0.).into();
-    let world: Vec<Hitable> = vec![
-        StaticSphere::new(1000., (0., -1000., 0.), Lambertian::new(text.clone())).into(),
-        StaticSphere::new(2., (0., 2., 0.), Lambertian::new(text)).into(),
-    ];
-    Hitable::List(world.into())
-}
-
-fn cornell_box() -> Hitable {
-    use libtrace::rect::{XYRect, XZRect, YZRect};
-    use libtrace::NormalFlipper;
-    let red: Material = Lambertian::new(Color::new((0.65, 0.05, 0.05))).into();
-    let white: Material = Lambertian::new(Color::new(0.73)).into();
-    let green: Material = Lambertian::new(Color::new((0.12, 0.45, 0.15))).into();
-    let light: Material = Diffuse::new(Color::new(15.)).into();
-    let world: Vec<Hitable> = vec![
-        NormalFlipper::new(YZRect::new(0., 555., 0., 555., 555., green)).into(), // left
-        YZRect::new(0., 555., 0., 555., 0., red).into(),                         // right
-        NormalFlipper::new(XZRect::new(213., 343., 227., 332., 554., light)).into(), // light
-        XZRect::new(0., 555., 0., 555., 0., white.clone()).into(),               // floor
-        NormalFlipper::new(XZRect::new(0., 555., 0., 555., 555., white.clone())).into(), // ceiling,
-        NormalFlipper::new(XYRect::new(0., 555., 0., 555., 555., white.clone())).into(),
-    ];
-    Hitable::List(world.into())
-}
-
-fn random_scene() -> Hitable {
-    use texture::Color;
-    let mut world: Vec<Hitable> = Vec::new();
-
-    fn metal(color: (f32, f32, f32), fuzz: f32) -> Material {
-        Metal::new(Color::new(color), fuzz).into()
-    }
-
-    fn lambert(color: (f32, f32, f32)) -> Material {
-        Lambertian::new(Color::new(color)).into()
-    }
-
-    world.push(
-        StaticSphere::new(
-            1000.,
-            (0., -1000., 0.),
-            Lambertian::new(CheckerBoard::new(
-                10.,
-                Color::new((0.8, 0.8, 0.8)),
-                Color::new((0.2, 0.3, 0.1)),
-            )),
-        )
-        .into(),
-    );
-
-    let mut rng = rand::thread_rng();
-
-    for a in -3..=3 {
-        for b in -3..=3 {
-            let a = a as f32;
-            let b = b as f32;
-            let material_type: f32 = rng.gen();
-            let center = Vec3::new(a + 0.9 * rng.gen::<f32>(), 0.2, b + 0.9 * rng.gen::<f32>());
-            if (center - Vec3::new(4., 0.2, 0.)).length() <= 0.9 {
-                continue;
-            }
-            let material: Material = if material_type < 0.8 {
-                lambert((
-                    rng.gen::<f32>() * rng.gen::<f32>(),
-                    rng.gen::<f32>() * rng.gen::<f32>(),
-                    rng.gen::<f32>() * rng.gen::<f32>(),
-                ))
-            } else if material_type < 0.95 {
-                metal(
-                    (
-                        0.5 * (1. + rng.gen::<f32>()),
-                        0.5 * (1. + rng.gen::<f32>()),
-                        0.5 * (1. + rng.gen::<f32>()),
-                    ),
-                    0.5 * rng.gen::<f32>(),
-                )
-            } else {
-                Dialectric::new(1.5).into()
-            };
-
-            let sphere: Hitable = if material_type < 0.8 {
-                MovingSphere::new(
-                    0.2,
-                    material,
-                    (0.0, center),
-                    (1.0, center + Vec3::new(0., 0.5 * rng.gen::<f32>(), 0.)),
-                )
-                .into()
-            } else {
-                StaticSphere::new(0.2, center, material).into()
-            };
-
-            world.push(sphere);
-        }
-    }
-
-    world.push(StaticSphere::new(1.0, (0., 1., 0.), Dialectric::new(1.5)).into());
-
-    world.push(StaticSphere::new(1.0, (-4., 1., 0.), lambert((0.4, 0.2, 0.1))).into());
-
-    world.push(StaticSphere::new(1.0, (4., 1., 0.), metal((0.7, 0.6, 0.5), 0.0)).into());
-
-    Hitable::BvhNode(BvhNode::new(world, (0.0, 1.0)))
-}
+/// How many samples of every pixel to take in each pass when passes are
+/// needed but not rendering progressively
+const PASS_SAMPLES: u32 = 16;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = clap::App::new("Tracer")
@@ -179,13 +32,127 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .takes_value(true)
                 .default_value("scene.yml"),
         )
+        .arg(
+            clap::Arg::with_name("bvh")
+                .long("bvh")
+                .value_name("SPLIT")
+                .help("How to split up the scene's bounding volume hierarchy")
+                .takes_value(true)
+                .possible_values(&["sah", "median"])
+                .case_insensitive(true),
+        )
+        .arg(
+            clap::Arg::with_name("precision")
+                .long("precision")
+                .value_name("PRECISION")
+                .help("How many bits to store each channel of an EXR image in")
+                .takes_value(true)
+                .possible_values(&["half", "float"])
+                .case_insensitive(true)
+                .default_value("half"),
+        )
+        .arg(
+            clap::Arg::with_name("heatmap")
+                .long("heatmap")
+                .value_name("FILE")
+                .help("Also writes a PNG showing how many samples each pixel took")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Where the random numbers used to render start from")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("sampler")
+                .long("sampler")
+                .value_name("SAMPLER")
+                .help("How to pick the random numbers for each sample")
+                .takes_value(true)
+                .possible_values(&["independent", "stratified", "halton", "sobol"])
+                .case_insensitive(true),
+        )
+        .arg(
+            clap::Arg::with_name("exposure")
+                .long("exposure")
+                .value_name("STOPS")
+                .help("How many stops to brighten the image by")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            clap::Arg::with_name("tone-map")
+                .long("tone-map")
+                .value_name("OPERATOR")
+                .help("How to bring bright colors into range")
+                .takes_value(true)
+                .possible_values(&["clamp", "reinhard", "filmic", "aces"])
+                .case_insensitive(true),
+        )
+        .arg(
+            clap::Arg::with_name("dither")
+                .long("dither")
+                .help("Adds noise before quantizing to hide banding"),
+        )
+        .arg(
+            clap::Arg::with_name("progressive")
+                .long("progressive")
+                .value_name("SAMPLES")
+                .help(
+                    "Renders in passes of this many samples per pixel, saving the image after each",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("snapshots")
+                .long("snapshots")
+                .help("Saves every pass to its own numbered file as well")
+                .requires("progressive"),
+        )
+        .arg(
+            clap::Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("Saves the render so far to this file after every pass")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("resume")
+                .long("resume")
+                .help("Carries on from the checkpoint, as long as the scene hasn't changed")
+                .requires("checkpoint"),
+        )
+        .arg(
+            clap::Arg::with_name("time-limit")
+                .long("time-limit")
+                .value_name("SECONDS")
+                .help("Stops starting passes once the next one wouldn't finish in time")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("PIXELS")
+                .help("How wide and high the tiles that are rendered together are")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tile-order")
+                .long("tile-order")
+                .value_name("ORDER")
+                .help("Which order to render the tiles in")
+                .takes_value(true)
+                .possible_values(&["scanline", "spiral", "hilbert"])
+                .case_insensitive(true),
+        )
         .get_matches();
 
-    use libtrace::{renderer::Renderer, scene::Scene};
-
     struct WorkstationRenderer<'a> {
-        objects: &'a Hitable,
         scene: &'a Scene,
+        objects: &'a Hitable,
+        lights: &'a Lights,
         progress_bar: &'a ProgressBar,
     }
 
@@ -199,44 +166,203 @@ fn main() -> Result<(), Box<dyn Error>> {
             self.objects
         }
 
-        fn render(&self) -> Vec<(u8, u8, u8)> {
-            let scene = self.scene();
-            let camera = self.camera(&scene);
-
-            self.get_pixels_to_render(&scene)
-                .into_par_iter()
-                .map(|(i, j)| self.render_pixel(&camera, (i, j), &scene))
-                .collect()
+        fn lights(&self) -> &Lights {
+            self.lights
         }
+
         #[inline]
-        fn on_pixel_rendered(&self, _location: (u32, u32), _color: (u8, u8, u8)) {
+        fn on_pixel_rendered(&self, _location: (u32, u32), _color: Vec3) {
+            self.progress_bar.inc(1);
+        }
+
+        fn on_pixel_skipped(&self, _location: (u32, u32)) {
             self.progress_bar.inc(1);
         }
     }
 
-    let mut scene: Scene =
-        serde_yaml::from_reader(fs::File::open(matches.value_of("input").unwrap())?)?;
-    let num_pixels = scene.image.num_pixels();
-    let progress_bar = ProgressBar::new(num_pixels as u64);
+    let mut scene = Scene::open(matches.value_of("input").unwrap())?;
+    if let Some(split) = matches.value_of("bvh") {
+        scene.bvh.split = split.parse()?;
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        scene.seed = seed.parse()?;
+    }
+    if let Some(sampler) = matches.value_of("sampler") {
+        scene.sampler = sampler.parse()?;
+    }
+    let tone_mapping = &mut scene.image.tone_mapping;
+    if let Some(exposure) = matches.value_of("exposure") {
+        tone_mapping.exposure = exposure.parse()?;
+    }
+    if let Some(operator) = matches.value_of("tone-map") {
+        tone_mapping.operator = operator.parse()?;
+    }
+    if matches.is_present("dither") {
+        tone_mapping.dither = true;
+    }
+    if let Some(size) = matches.value_of("tile-size") {
+        scene.image.tiles.size = size.parse()?;
+    }
+    if let Some(order) = matches.value_of("tile-order") {
+        scene.image.tiles.order = order.parse()?;
+    }
+    if let Some(time_limit) = matches.value_of("time-limit") {
+        scene.image.time_limit = Some(time_limit.parse()?);
+    }
+    let image = scene.image.clone();
+    let scene_hash = {
+        let mut scene = scene.clone();
+        // Taking more samples, for longer, tone mapping differently, or
+        // splitting up the work differently doesn't change what's been
+        // rendered so far
+        scene.image.samples = 0;
+        scene.image.time_limit = None;
+        scene.image.tone_mapping = Default::default();
+        scene.image.tiles = Default::default();
+        scene.bvh = Default::default();
+        let mut bytes = serde_yaml::to_string(&scene)?.into_bytes();
+        // The models and images it loads can change without the scene
+        // file changing
+        for file in &scene.files {
+            bytes.extend(fs::read(file)?);
+        }
+        checkpoint::fingerprint(&bytes)
+    };
+    let checkpoint_path = matches.value_of("checkpoint").map(Path::new);
+    let (mut film, mut samples_taken) = match checkpoint_path {
+        Some(path) if matches.is_present("resume") => {
+            let checkpoint = Checkpoint::read(&mut BufReader::new(fs::File::open(path)?))?;
+            if checkpoint.scene_hash != scene_hash {
+                return Err(format!(
+                    "the scene has changed since {} was saved, so it can't be resumed",
+                    path.display()
+                )
+                .into());
+            }
+            eprintln!("Resuming after {} samples", checkpoint.samples_taken);
+            (checkpoint.film, checkpoint.samples_taken)
+        }
+        _ => (image.film(), 0),
+    };
+    let samples_per_pass = match matches.value_of("progressive") {
+        Some(samples) => samples.parse()?,
+        // Checkpoints are only saved, and the time limit only checked,
+        // between passes
+        None if checkpoint_path.is_some() || image.time_limit.is_some() => PASS_SAMPLES,
+        None => image.samples,
+    };
+    let remaining = Image {
+        first_sample: image.first_sample + samples_taken,
+        samples: image.samples.saturating_sub(samples_taken),
+        total_samples: Some(image.total_samples()),
+        ..image.clone()
+    };
+    let passes: Vec<_> = remaining.passes(samples_per_pass).collect();
+    let progress_bar = ProgressBar::new(u64::from(image.num_pixels()) * passes.len() as u64);
 
     progress_bar.set_style(ProgressStyle::default_bar().template(
         "[{elapsed_precise} elapsed] {wide_bar:.green/white} {percent}% [{eta} remaining]",
     ));
 
+    let objects = scene.objects.clone().into_bvh((0.0, 1.0), &scene.bvh);
+    if let Hitable::BvhNode(node) = &objects {
+        eprintln!("BVH: {}", node.stats());
+    }
+    let lights = scene.lights();
+    eprintln!("Lights: {}", lights.len());
+
+    let output_path = Path::new(matches.value_of("output").unwrap());
+    let precision = matches.value_of("precision").unwrap().parse()?;
+    let num_passes = passes.len();
+    let mut budget = TimeBudget::new(image.time_limit());
+    let mut samples_this_run = 0;
     let renderer = WorkstationRenderer {
         progress_bar: &progress_bar,
         scene: &scene,
-        objects: &cornell_box(),
+        objects: &objects,
+        lights: &lights,
     };
+    for (number, pass) in passes.into_iter().enumerate() {
+        if !budget.has_time_for_pass() {
+            break;
+        }
+        let rendered = budget.time_pass(|| renderer.render_pass(&pass, Some(&film)));
+        film.merge(&rendered);
+        samples_taken += pass.samples;
+        samples_this_run += pass.samples;
+
+        if let Some(path) = checkpoint_path {
+            let checkpoint = Checkpoint {
+                scene_hash,
+                samples_taken,
+                film,
+            };
+            write_atomically(path, |output| Ok(checkpoint.write(output)?))?;
+            film = checkpoint.film;
+        }
+        let visible = visible_pixels(&image, &film);
+        if matches.is_present("snapshots") {
+            save(
+                &snapshot_path(output_path, number + 1),
+                &visible,
+                &image,
+                precision,
+            )?;
+        }
+        // Rendering progressively rewrites the output after every pass,
+        // so a render that's stopped early keeps it
+        if matches.is_present("progressive") && number + 1 < num_passes {
+            save(output_path, &visible, &image, precision)?;
+        }
+    }
+    progress_bar.finish();
+    eprintln!(
+        "Took {} samples per pixel in {:.1}s",
+        samples_this_run,
+        budget.elapsed().as_secs_f32()
+    );
+    let film = visible_pixels(&image, &film);
+    save(output_path, &film, &image, precision)?;
+
+    if let Some(path) = matches.value_of("heatmap") {
+        let mut heatmap = fs::File::create(path)?;
+        libtrace::renderer::write_png(
+            &mut heatmap,
+            (film.width(), film.height()),
+            &film.heatmap(),
+        )?;
+    }
+    Ok(())
+}
 
-    let pixels = renderer.render();
+/// Just the pixels of `film` that `image` renders, leaving out the margin
+/// around them that the filter spreads samples into
+fn visible_pixels(image: &Image, film: &Film) -> Film {
+    let mut visible = image.visible_film();
+    visible.merge(film);
+    visible
+}
 
-    let mut output = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(matches.value_of("output").unwrap())?;
+/// Saves `film` in the format that `path`'s extension asks for
+fn save(
+    path: &Path,
+    film: &Film,
+    image: &Image,
+    precision: Precision,
+) -> Result<(), Box<dyn Error>> {
+    let format = Format::from_path(path, precision);
+    write_atomically(path, |output| {
+        format.write(output, film, &image.tone_mapping)
+    })
+}
 
-    renderer.write_image(&mut output, &pixels)?;
-    Ok(())
+/// Where to save the snapshot of pass `number`, which is numbered after
+/// the name of the output, like `image-0001.png`
+fn snapshot_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}-{:04}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}-{:04}", stem, number),
+    };
+    path.with_file_name(file_name)
 }
@@ -0,0 +1,49 @@
+//! Serves the same `/render` endpoint as the Lambda worker, for running
+//! workers on our own machines
+
+use env_logger::{Builder, Env};
+use std::error::Error;
+use tiny_http::Server;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    Builder::from_env(Env::default().filter_or("TRACER_LOG", "info")).init();
+    let matches = clap::App::new("Worker")
+        .version("0.1.0")
+        .about("Renders the scenes posted to /render")
+        .arg(
+            clap::Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("The address and port to listen on")
+                .takes_value(true)
+                .default_value("127.0.0.1:8080"),
+        )
+        .arg(
+            clap::Arg::with_name("threads")
+                .long("threads")
+                .value_name("THREADS")
+                .help("How many threads to render with. Uses every core by default.")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("concurrency")
+                .long("concurrency")
+                .value_name("SCENES")
+                .help("How many scenes to render at once")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .get_matches();
+
+    if let Some(threads) = matches.value_of("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.parse()?)
+            .build_global()?;
+    }
+    let concurrency: usize = matches.value_of("concurrency").unwrap().parse()?;
+    let server = Server::http(matches.value_of("bind").unwrap()).map_err(|err| err.to_string())?;
+    log::info!("Listening on {}", server.server_addr());
+
+    worker::server::serve(&server, concurrency);
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Renders the scenes sent to workers, whether they're running on Lambda
+//! or as a server on our own machines
+
+pub mod server;
+
+use libtrace::{
+    load::{self, Files},
+    renderer::Renderer,
+    scene::{Rendered, Scene},
+    Hitable, Lights,
+};
+
+/// How many samples of each pixel to take between checking the time
+const SAMPLES_PER_PASS: u32 = 8;
+
+struct WorkerRenderer<'a> {
+    scene: &'a Scene,
+    objects: Hitable,
+    lights: Lights,
+}
+
+impl<'a> Renderer for WorkerRenderer<'a> {
+    fn objects(&self) -> &Hitable {
+        &self.objects
+    }
+    fn lights(&self) -> &Lights {
+        &self.lights
+    }
+    fn scene(&self) -> &Scene {
+        self.scene
+    }
+}
+
+/// Reads the scene in a request to render it. The request can carry
+/// the contents of every file the scene loads, under `files`, for
+/// workers that can't see those files. The scene is then loaded from
+/// those alone.
+pub fn scene_from_request(body: &[u8]) -> serde_json::Result<Scene> {
+    let mut request: serde_json::Value = serde_json::from_slice(body)?;
+    let files = request
+        .as_object_mut()
+        .and_then(|request| request.remove("files"));
+    match files {
+        Some(files) => {
+            let files: Files = serde_json::from_value(files)?;
+            load::from_files(files, || serde_json::from_value(request))
+        }
+        None => serde_json::from_value(request),
+    }
+}
+
+/// Renders the rows and samples that the scene's image asks for, or as
+/// many of the samples as fit in its time limit
+pub fn render(scene: Scene) -> Rendered {
+    let objects = scene.objects.clone().into_bvh((0.0, 1.0), &scene.bvh);
+    if let Hitable::BvhNode(node) = &objects {
+        log::info!("BVH: {}", node.stats());
+    }
+    let renderer = WorkerRenderer {
+        scene: &scene,
+        objects,
+        lights: scene.lights(),
+    };
+    log::info!(
+        "Rendering {}x{} pixels at {} samples",
+        scene.image.width(),
+        scene.image.height(),
+        scene.image.samples
+    );
+    let (film, samples_taken) = renderer.render_passes(SAMPLES_PER_PASS);
+    log::info!("Took {} samples per pixel", samples_taken);
+
+    // The image says how many samples were actually taken, which is
+    // fewer than were asked for if time ran out
+    let mut image = scene.image;
+    image.samples = samples_taken;
+    Rendered { image, film }
+}
@@ -1,19 +1,13 @@
 use env_logger::{Builder, Env};
 use lambda_http::{lambda, IntoResponse, Request};
 use lambda_runtime::{error::HandlerError, Context};
-use libtrace::{
-    renderer::Renderer,
-    scene::{Rendered, Scene},
-    Hitable,
-};
+
+/// How many seconds to spend rendering at most. Lambda's timeout is 60
+/// seconds, in `serverless.yml`.
+const TIME_LIMIT: f32 = 50.;
 
 fn main() {
-    Builder::from_env(
-        Env::default()
-            .filter("TRACER_LOG")
-            .default_filter_or("warn"),
-    )
-    .init();
+    Builder::from_env(Env::default().filter_or("TRACER_LOG", "warn")).init();
     lambda!(handler)
 }
 
@@ -21,26 +15,11 @@ fn handler(request: Request, _: Context) -> Result<impl IntoResponse, HandlerErr
     log::info!("Received request");
     let body = request.body();
 
-    let scene: Scene = serde_json::from_slice(body)?;
-
-    struct WorkerRenderer<'a> {
-        scene: &'a Scene,
-    }
-
-    impl<'a> Renderer for WorkerRenderer<'a> {
-        fn objects(&self) -> &Hitable {
-            unimplemented!()
-        }
-        fn scene(&self) -> &Scene {
-            self.scene
-        }
-    }
-
-    let renderer = WorkerRenderer { scene: &scene };
-    let pixels = renderer.render();
+    let mut scene = worker::scene_from_request(body)?;
+    // Lambda stops the worker after a minute, so leave time to send the
+    // film back
+    let time_limit = scene.image.time_limit.unwrap_or(TIME_LIMIT);
+    scene.image.time_limit = Some(time_limit.min(TIME_LIMIT));
 
-    Ok(serde_json::to_string(&Rendered {
-        image: scene.image,
-        pixels,
-    })?)
+    Ok(serde_json::to_string(&worker::render(scene))?)
 }
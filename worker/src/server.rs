@@ -0,0 +1,54 @@
+//! Serves the same `/render` endpoint as the Lambda worker, for running
+//! workers on our own machines
+
+use std::error::Error;
+use std::panic;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Answers requests to `server` until it's stopped, rendering up to
+/// `concurrency` scenes at once
+pub fn serve(server: &Server, concurrency: usize) {
+    // Every thread takes the next request as soon as it's free, so any
+    // more than `concurrency` wait their turn
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    if let Err(err) = respond(request) {
+                        log::warn!("Couldn't respond: {}", err);
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn respond(mut request: Request) -> Result<(), Box<dyn Error>> {
+    if request.url() != "/render" {
+        return Ok(request.respond(Response::from_string("Not found").with_status_code(404))?);
+    }
+    if *request.method() != Method::Post {
+        return Ok(request.respond(Response::from_string("Use POST").with_status_code(405))?);
+    }
+    log::info!("Received request");
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+    let scene = match crate::scene_from_request(&body) {
+        Ok(scene) => scene,
+        Err(err) => {
+            let message = format!("Invalid scene: {}", err);
+            return Ok(request.respond(Response::from_string(message).with_status_code(400))?);
+        }
+    };
+
+    // A scene that can't be rendered shouldn't take the thread with it
+    let response = match panic::catch_unwind(|| crate::render(scene)) {
+        Ok(rendered) => {
+            let json = Header::from_bytes("Content-Type", "application/json").unwrap();
+            Response::from_string(serde_json::to_string(&rendered)?).with_header(json)
+        }
+        Err(_) => Response::from_string("Rendering failed").with_status_code(500),
+    };
+    Ok(request.respond(response)?)
+}